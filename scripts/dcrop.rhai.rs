@@ -0,0 +1,6 @@
+let pq = select_drag();
+if type_of(pq) == "array" {
+    crop(0, 0, pq[0], pq[1], pq[2], pq[3]);
+    save(0, arg("output", "{stem}_cropped.{ext}"));
+}
+kill_on("return");
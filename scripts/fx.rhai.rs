@@ -2,6 +2,7 @@
 while true {
     let e = get_input_event();
     if e.is_click { continue; }
+    if history_keys(e) { continue; }
     if e.key == "termination" {
         break;
     }
@@ -12,10 +13,10 @@ while true {
         grayscale(0, 0);
     }
     else if e.key == 'g' && e.shift {
-        blur(0, 0, 1.0);
+        blur(0, 0, arg("sigma", 1.0));
     }
     else if e.key == 'u' {
-        unsharpen(0, 0, 1.0, 1);
+        unsharpen(0, 0, arg("sigma", 1.0), arg("threshold", 1));
     }
     else if e.key == 'c' && !e.shift {
         adjust_contrast(0, 0, 4.0);
@@ -36,7 +37,7 @@ while true {
         huerotate(0, 0, -4);
     }
     else if e.key == "return" {
-        save(0, "outp.jpg");
+        save(0, arg("output", "outp.jpg"));
         print("haha");
         break;
     }
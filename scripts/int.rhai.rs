@@ -1,123 +1,88 @@
+// keys per mode, press ? to see the ones of the current mode
+bind_view_keys();
+bind_history_keys();
 
-print("[NORMAL]\n");
-while true {
-    let e = get_input_event();
-    if e.key == "termination" { break; }
-    if e.key == "lshift" || e.key == "rshift" {
-        continue;
-    }
-    if e.key == "a" {
-        show_prev();
-    }
-    else if e.key == "o" {
-        show_next();
-    }
-    else if e.key == "s" {
-        let image = src();
-        let fname = file_input("file name: ");
-        if fname != "" {
-            save(image, fname);
-        }
-    }
-    else if e.key == "f" {
-        handle_fx();
-    }
-    else if e.key == "t" {
-        handle_transform();
-    }
-    else if e.key == "c" {
-        handle_crop();
-    }
-    else if e.key == "slash" && e.shift {
-        print("[HELP]\n");
-        print("a_prev, o_next, s_ave, f_x, t_ransform, c_rop, ._repeat\n");
-    }
-    else if e.key == "period" {
-        print("[REPEAT]\n");
-        repeat();
-    }
-    // print(`${e.key}`);
-}
-
-kill();
-
-fn src() { return nat_num_input("src: "); }
-fn dst() { return nat_num_input("dst: "); }
-
-fn handle_fx() {
-    print("[FX]");
-    while true {
-        let e = get_input_event();
-        if e.is_click { return; }
-        if e.key == "termination" { return; }
-        else if e.key == "lshift" || e.key == "rshift" {
-            continue;
-        } else if e.key == 'i' {
-            print("[INVERT]\n");
-            invert(src(), dst());
-        } else if e.key == 'g' && !e.shift {
-            print("[GRAYSCALE]\n");
-            grayscale(src(), dst());
-        } else if e.key == 'g' && e.shift {
-            print("[GAUSSIAN]\n");
-            blur(src(), dst(), float_input("sigma: "));
-        } else if e.key == 'u' {
-            print("[UNSHARPEN]\n");
-            unsharpen(src(), dst(), float_input("sigma: "), nat_num_input("threshold: "));
-        } else if e.key == 'c' {
-            print("[CONTRAST]\n");
-            adjust_contrast(src(), dst(), float_input("contrast: "));
-        } else if e.key == 'b' {
-            print("[BRIGHTEN]\n");
-            brighten(src(), dst(), nat_num_input("val: "));
-        } else if e.key == 'h' {
-            print("[HUEROTATE]\n");
-            huerotate(src(), dst(), nat_num_input("val: "));
-        } else if e.key == "slash" && e.shift {
-            print("[HELP]\n");
-            print("i_nvert, g_rayscale, G_aussian, u_nsharpen, c_ontrast, b_righten, h_uerotate\n");
-            continue;
-        }
-        break;
-    }
-}
-
-fn handle_transform() {
-    print("[TRANSFORM]");
-    while true {
-        let e = get_input_event();
-        if e.is_click { return; }
-        if e.key == "termination" { return; }
-        else if e.key == "lshift" || e.key == "rshift" {
-            continue;
-        } else if e.key == 'o' {
-            print("[ROTATE90]\n");
-            rotate90(0, 0);
-        } else if e.key == 'a' {
-            print("[ROTATE270]\n");
-            rotate270(0, 0);
-        } else if e.key == 'e' {
-            print("[FLIPH]\n");
-            fliph(0, 0);
-        } else if e.key == 'u' {
-            print("[FLIPV]\n");
-            flipv(0, 0);
-        } else if e.key == "slash" && e.shift {
-            print("[HELP]\n");
-            print("o_rotate90, a_rotate270, e_fliph, u_flipv\n");
-            continue;
-        }
-        break;
-    }
-}
-
-fn handle_crop() {
-    print("[CROP]\n");
+bind("normal", "a", "previous buffer", || show_prev());
+bind("normal", "o", "next buffer", || show_next());
+bind("normal", "s", "save", || {
+    let image = src();
+    save(image, required(prompt_text("file name: ")));
+});
+bind("normal", "f", "fx", || enter_mode("fx"));
+bind("normal", "t", "transform", || enter_mode("transform"));
+bind("normal", "c", "crop", || {
     let d = dst();
-    let square = bool_input("Square selection y/n: ");
-
+    let square = required(prompt_bool("Square selection ", false));
     let pq = select(0, square);
     crop(shown(), d, pq[0], pq[1], pq[2], pq[3]);
     show(d);
-}
+});
+bind("normal", "period", "repeat", || repeat());
+
+// every action goes back to normal first, so a cancelled prompt does too
+bind("fx", "i", "invert", || {
+    enter_mode("normal");
+    invert(src(), dst());
+});
+bind("fx", "g", "grayscale", || {
+    enter_mode("normal");
+    grayscale(src(), dst());
+});
+bind("fx", "G", "gaussian blur", || {
+    enter_mode("normal");
+    let s = src();
+    let d = dst();
+    blur(s, d, required(prompt_float("sigma: ", 1.0)));
+});
+bind("fx", "u", "unsharpen", || {
+    enter_mode("normal");
+    let s = src();
+    let d = dst();
+    let sigma = required(prompt_float("sigma: ", 1.0));
+    unsharpen(s, d, sigma, required(prompt_int("threshold: ", 0)));
+});
+bind("fx", "c", "contrast", || {
+    enter_mode("normal");
+    let s = src();
+    let d = dst();
+    adjust_contrast(s, d, required(prompt_float("contrast: ", 0.0)));
+});
+bind("fx", "b", "brighten", || {
+    enter_mode("normal");
+    let s = src();
+    let d = dst();
+    brighten(s, d, required(prompt_int("val: ", 0)));
+});
+bind("fx", "h", "huerotate", || {
+    enter_mode("normal");
+    let s = src();
+    let d = dst();
+    huerotate(s, d, required(prompt_int("val: ", 0)));
+});
+bind("fx", "q", "back", || enter_mode("normal"));
+
+bind("transform", "o", "rotate 90", || {
+    enter_mode("normal");
+    rotate90(shown(), shown());
+});
+bind("transform", "a", "rotate 270", || {
+    enter_mode("normal");
+    rotate270(shown(), shown());
+});
+bind("transform", "e", "flip horizontal", || {
+    enter_mode("normal");
+    fliph(shown(), shown());
+});
+bind("transform", "u", "flip vertical", || {
+    enter_mode("normal");
+    flipv(shown(), shown());
+});
+bind("transform", "q", "back", || enter_mode("normal"));
+
+enter_mode("normal");
+run_keymap();
+
+kill();
 
+fn src() { return required(prompt_int("src: ", shown())); }
+fn dst() { return required(prompt_int("dst: ", shown())); }
@@ -20,6 +20,7 @@ fn select(step, square) {
         let oqy = qy;
         let e = get_input_event();
         if e.key == "termination" { break; }
+        if view_keys(e) { continue; }
         if e.shift {
             step = 1;
         } else {
@@ -36,6 +37,9 @@ fn select(step, square) {
         } else if e.key == "return" {
             break;
         }
+        else if e.key == "z" { // zoom in on the selection
+            zoom_to_rect(px, py, qx, qy);
+        }
         else if e.key == "s" { // move left
             px -= step;
             qx -= step;
@@ -106,111 +110,90 @@ fn select(step, square) {
             draw_rect_xy(px, py, qx, qy);
         }
     }
+    // the selection may reach outside the image, crop wants the corners within it
+    px = max(0, min(px, w));
+    py = max(0, min(py, h));
+    qx = max(0, min(qx, w));
+    qy = max(0, min(qy, h));
     return [px, py, qx, qy];
 }
 
 fn file_input(msg) {
-    let string = "";
-    print(msg);
-
-    while true {
-        let e = get_input_event();
-        if e.key == "termination" { break; }
-        if e.is_click { continue; }
-        if ["num0", "num1", "num2", "num3", "num4", "num5", "num6", "num7", "num8", "num9", "num0",
-        "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r",
-        "s", "t", "u", "v", "w", "x", "y", "z"].contains(e.key) {
-            string += e.key;
-            print(e.key[e.key.len() - 1]);
-        } else if e.key == "period" {
-            string += ".";
-            print(".");
-        } else if e.key == "minus" {
-            string += "-";
-            print("-");
-        } else if e.key == "backspace" && string.len() > 0 {
-            string.pop();
-            print("\x08");
-            print(" ");
-            print("\x08");
-        } else if e.key == "return" {
-            break;
-        } else if e.key == "backslash" {
-            return "";
-        }
+    let name = prompt_text(msg);
+    if type_of(name) == "()" {
+        return "";
     }
-
-    print("\n");
-    return string;
+    return name;
 }
 
-fn nat_num_input(msg) {
-    let number = "0";
-    print(msg);
-    print(number);
+// throws when a prompt was cancelled, so a command can bail out with try/catch
+fn required(answer) {
+    if type_of(answer) == "()" {
+        throw "cancelled";
+    }
+    return answer;
+}
 
-    while true {
-        let e = get_input_event();
-        if e.key == "termination" { break; }
-        if e.is_click { continue; }
-        if e.key == "num0" {
-            number += "0";
-            print("0");
-        } else if e.key == "num1" {
-            number += "1";
-            print("1");
-        } else if e.key == "num2" {
-            number += "2";
-            print("2");
-        } else if e.key == "num3" {
-            number += "3";
-            print("3");
-        } else if e.key == "num4" {
-            number += "4";
-            print("4");
-        } else if e.key == "num5" {
-            number += "5";
-            print("5");
-        } else if e.key == "num6" {
-            number += "6";
-            print("6");
-        } else if e.key == "num7" {
-            number += "7";
-            print("7");
-        } else if e.key == "num8" {
-            number += "8";
-            print("8");
-        } else if e.key == "num9" {
-            number += "9";
-            print("9");
-        } else if e.key == "backspace" && number.len() > 1 {
-            number.pop();
-            print("\x08");
-            print(" ");
-            print("\x08");
-        } else if e.key == "return" {
-            break;
-        }
+fn view_keys(e) {
+    if e.is_click { return false; }
+    if e.key == "equals" || e.key == "plus" || e.key == "kpplus" {
+        zoom(1.25);
+    } else if e.key == "minus" || e.key == "kpminus" {
+        zoom(0.8);
+    } else if e.key == "num0" && e.control {
+        zoom_to_fit();
+    } else if e.key == "left" {
+        pan(32, 0);
+    } else if e.key == "right" {
+        pan(-32, 0);
+    } else if e.key == "up" {
+        pan(0, 32);
+    } else if e.key == "down" {
+        pan(0, -32);
+    } else {
+        return false;
     }
+    return true;
+}
 
-    let parsed = parse_int(number);
-    let res = if type_of(parsed) == "i64" {
-        print(" [parsed]");
-        parsed
+fn history_keys(e) {
+    if e.is_click || !e.control { return false; }
+    if e.key == "z" && !e.shift {
+        undo(shown());
+    } else if (e.key == "z" && e.shift) || e.key == "y" {
+        redo(shown());
     } else {
-        print(" [failed!]");
-        -1
-    };
-    print("\n");
+        return false;
+    }
+    return true;
+}
+
+// the keys of view_keys and history_keys for run_keymap, in every mode
+fn bind_view_keys() {
+    bind("*", "equals", "zoom in", || zoom(1.25));
+    bind("*", "S-equals", "zoom in", || zoom(1.25));
+    bind("*", "kpplus", "zoom in", || zoom(1.25));
+    bind("*", "minus", "zoom out", || zoom(0.8));
+    bind("*", "kpminus", "zoom out", || zoom(0.8));
+    bind("*", "C-num0", "zoom to fit", || zoom_to_fit());
+    bind("*", "left", "pan left", || pan(32, 0));
+    bind("*", "right", "pan right", || pan(-32, 0));
+    bind("*", "up", "pan up", || pan(0, 32));
+    bind("*", "down", "pan down", || pan(0, -32));
+}
 
-    return res;
+fn bind_history_keys() {
+    bind("*", "C-z", "undo", || undo(shown()));
+    bind("*", "C-Z", "redo", || redo(shown()));
+    bind("*", "C-y", "redo", || redo(shown()));
 }
 
 fn kill_on(key) {
     while true {
         let e = get_input_event();
-        if e.key == key {
+        if e.key == key || e.key == "termination" {
             kill();
+            return;
         }
     }
 }
@@ -0,0 +1,4 @@
+// Does nothing on its own, meant to be started with -i to get a plain REPL.
+print("Buffers: ");
+print(`${get_buffers_len()}`);
+print("\n");
@@ -0,0 +1,4 @@
+// editimg --batch resize_web *.jpg
+let size = arg("size", 1600);
+resize(0, 0, size, size, "lanczos3");
+save(0, arg("output", "{stem}_web.jpg"));
@@ -1,5 +1,5 @@
 let pq = select(0, true);
 crop(0, 0, pq[0], pq[1], pq[2], pq[3]);
-save(0, "cropped.jpg");
+save(0, arg("output", "{stem}_cropped.{ext}"));
 kill_on("return");
 
@@ -44,9 +44,9 @@ print(" x ");
 print(`${height}`);
 print("\n");
 
-let square = bool_input("Save result y/n: ");
+let save_result = prompt_bool("Save result ", false);
 
-if square {
+if save_result == true {
     let fname = file_input("file name: ");
     if fname != "" {
         save(result, fname);
@@ -55,5 +55,3 @@ if square {
 
 kill();
 
-fn src() { return nat_num_input("src: "); }
-
@@ -2,6 +2,7 @@
 while true {
     let e = get_input_event();
     if e.is_click { continue; }
+    if history_keys(e) { continue; }
     if e.key == "termination" {
         break;
     } else if e.key == 'o' {
@@ -13,7 +14,7 @@ while true {
     } else if e.key == 'u' {
         flipv(0, 0);
     } else if e.key == "return" {
-        save(0, "transformed.jpg");
+        save(0, arg("output", "transformed.jpg"));
         break;
     }
 }
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+/// Puts the results of a batch, which come in as the images finish, back in the order of the
+/// images.
+pub struct InOrder<T> {
+    next: usize,
+    waiting: HashMap<usize, T>,
+}
+
+impl<T> Default for InOrder<T> {
+    fn default() -> Self {
+        Self { next: 0, waiting: HashMap::new() }
+    }
+}
+
+impl<T> InOrder<T> {
+    /// Result i is in, returns the results that are next in order with their index.
+    pub fn push(&mut self, i: usize, result: T) -> Vec<(usize, T)> {
+        self.waiting.insert(i, result);
+        let mut ready = Vec::new();
+        while let Some(result) = self.waiting.remove(&self.next) {
+            ready.push((self.next, result));
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+
+    #[test]
+    fn test_in_order(){
+        let mut order = InOrder::default();
+        assert!(order.push(2, "c").is_empty());
+        assert!(order.push(1, "b").is_empty());
+        assert_eq!(order.push(0, "a"), vec![(0, "a"), (1, "b"), (2, "c")]);
+        assert_eq!(order.push(3, "d"), vec![(3, "d")]);
+        assert!(order.push(5, "f").is_empty());
+        assert_eq!(order.push(4, "e"), vec![(4, "e"), (5, "f")]);
+    }
+}
@@ -0,0 +1,294 @@
+use image::{ DynamicImage, ImageFormat };
+
+use std::{
+    collections::VecDeque,
+    ops::Index,
+    path::PathBuf,
+};
+
+pub struct Buffers {
+    images: Vec<DynamicImage>,
+    history: Vec<History>,
+    origins: Vec<Option<Origin>>,
+    undo_limit: usize,
+}
+
+/// The file a buffer was loaded from.
+#[derive(Debug, Clone, Default)]
+pub struct Origin {
+    pub path: PathBuf,
+    pub format: Option<ImageFormat>,
+    pub metadata: Metadata,
+}
+
+/// Kept to be written again by save_with.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub icc: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PathPart {
+    Path,
+    Dir,
+    Stem,
+    Ext,
+}
+
+#[derive(Default)]
+struct History {
+    undo: VecDeque<DynamicImage>,
+    redo: Vec<DynamicImage>,
+    bytes: usize,
+}
+
+impl Buffers {
+    /// undo_limit is the amount of bytes of history kept per buffer
+    pub fn new(undo_limit: usize) -> Self {
+        Self {
+            images: Vec::new(),
+            history: Vec::new(),
+            origins: Vec::new(),
+            undo_limit,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn push(&mut self, img: DynamicImage) -> usize {
+        self.images.push(img);
+        self.history.push(History::default());
+        self.origins.push(None);
+        self.images.len() - 1
+    }
+
+    pub fn origin(&self, i: usize) -> Option<&Origin> {
+        self.origins[i].as_ref()
+    }
+
+    pub fn set_origin(&mut self, i: usize, origin: Option<Origin>) {
+        self.origins[i] = origin;
+    }
+
+    /// Replace the buffer, the old image goes onto the undo stack.
+    pub fn replace(&mut self, i: usize, img: DynamicImage) {
+        let old = std::mem::replace(&mut self.images[i], img);
+        let h = &mut self.history[i];
+        h.redo.clear();
+        h.push_undo(old);
+        h.shrink(self.undo_limit);
+    }
+
+    pub fn undo(&mut self, i: usize) -> bool {
+        let h = &mut self.history[i];
+        if let Some(img) = h.undo.pop_back() {
+            h.bytes -= size(&img);
+            let cur = std::mem::replace(&mut self.images[i], img);
+            h.bytes += size(&cur);
+            h.redo.push(cur);
+            h.shrink(self.undo_limit);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn redo(&mut self, i: usize) -> bool {
+        let h = &mut self.history[i];
+        if let Some(img) = h.redo.pop() {
+            h.bytes -= size(&img);
+            let cur = std::mem::replace(&mut self.images[i], img);
+            h.push_undo(cur);
+            h.shrink(self.undo_limit);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn history_len(&self, i: usize) -> usize {
+        self.history[i].undo.len()
+    }
+}
+
+impl Index<usize> for Buffers {
+    type Output = DynamicImage;
+
+    fn index(&self, i: usize) -> &DynamicImage {
+        &self.images[i]
+    }
+}
+
+impl Origin {
+    /// The directory is "." for a bare file name, the extension falls back to the one of the
+    /// format for files without one.
+    pub fn part(&self, part: PathPart) -> String {
+        let lossy = |s: Option<&std::ffi::OsStr>| {
+            s.map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+        };
+        match part {
+            PathPart::Path => self.path.display().to_string(),
+            PathPart::Dir => match self.path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p.display().to_string(),
+                _ => ".".to_string(),
+            },
+            PathPart::Stem => lossy(self.path.file_stem()),
+            PathPart::Ext => match self.path.extension() {
+                Some(e) => e.to_string_lossy().into_owned(),
+                None => self.format
+                    .and_then(|f| f.extensions_str().first())
+                    .map(|e| e.to_string())
+                    .unwrap_or_default(),
+            },
+        }
+    }
+}
+
+impl PathPart {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "path" => Some(Self::Path),
+            "dir" => Some(Self::Dir),
+            "stem" => Some(Self::Stem),
+            "ext" => Some(Self::Ext),
+            _ => None,
+        }
+    }
+}
+
+/// Fill in {path}, {dir}, {stem} and {ext} from the origin of a buffer, `{{` and `}}` are
+/// literal braces.
+pub fn expand_template(template: &str, origin: Option<&Origin>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let Some(end) = tail.find('}').filter(|_| tail.starts_with('{')) else {
+            return Err(format!("unmatched brace in \"{template}\""));
+        };
+        let name = &tail[1..end];
+        let part = PathPart::from_name(name)
+            .ok_or_else(|| format!("unknown field {{{name}}} in \"{template}\""))?;
+        let origin = origin
+            .ok_or_else(|| format!("{{{name}}} needs a buffer that was loaded from a file"))?;
+        out.push_str(&origin.part(part));
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl History {
+    fn push_undo(&mut self, img: DynamicImage) {
+        self.bytes += size(&img);
+        self.undo.push_back(img);
+    }
+
+    // drop the oldest undo steps first, then the furthest redo steps
+    fn shrink(&mut self, limit: usize) {
+        while self.bytes > limit {
+            let img = if let Some(img) = self.undo.pop_front() {
+                img
+            } else if !self.redo.is_empty() {
+                self.redo.remove(0)
+            } else {
+                break;
+            };
+            self.bytes -= size(&img);
+        }
+    }
+}
+
+fn size(img: &DynamicImage) -> usize {
+    img.as_bytes().len()
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use image::RgbImage;
+
+    fn img(w: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::new(w, 1))
+    }
+
+    #[test]
+    fn test_undo_redo(){
+        let mut b = Buffers::new(usize::MAX);
+        let i = b.push(img(1));
+        b.replace(i, img(2));
+        b.replace(i, img(3));
+        assert_eq!(b.history_len(i), 2);
+        assert!(b.undo(i));
+        assert_eq!(b[i].width(), 2);
+        assert!(b.undo(i));
+        assert_eq!(b[i].width(), 1);
+        assert!(!b.undo(i));
+        assert!(b.redo(i));
+        assert_eq!(b[i].width(), 2);
+        b.replace(i, img(4));
+        assert!(!b.redo(i));
+        assert_eq!(b.history_len(i), 2);
+    }
+
+    #[test]
+    fn test_origin(){
+        let o = Origin {
+            path: PathBuf::from("shots/photo.JPG"), format: Some(ImageFormat::Jpeg),
+            ..Default::default()
+        };
+        assert_eq!(o.part(PathPart::Dir), "shots");
+        assert_eq!(o.part(PathPart::Stem), "photo");
+        assert_eq!(o.part(PathPart::Ext), "JPG");
+        let o = Origin {
+            path: PathBuf::from("photo"), format: Some(ImageFormat::Png), ..Default::default()
+        };
+        assert_eq!(o.part(PathPart::Dir), ".");
+        assert_eq!(o.part(PathPart::Ext), "png");
+
+        let mut b = Buffers::new(usize::MAX);
+        let i = b.push(img(1));
+        assert!(b.origin(i).is_none());
+        b.set_origin(i, Some(o));
+        assert_eq!(b.origin(i).unwrap().part(PathPart::Path), "photo");
+    }
+
+    #[test]
+    fn test_expand_template(){
+        let o = Origin { path: PathBuf::from("a/photo.jpg"), ..Default::default() };
+        let t = |s: &str| expand_template(s, Some(&o));
+        assert_eq!(t("{dir}/{stem}_crop.{ext}").unwrap(), "a/photo_crop.jpg");
+        assert_eq!(t("{{{stem}}}.png").unwrap(), "{photo}.png");
+        assert_eq!(t("out.png").unwrap(), "out.png");
+        assert!(t("{name}.png").is_err());
+        assert!(t("{stem.png").is_err());
+        assert!(t("stem}.png").is_err());
+        assert_eq!(expand_template("out.png", None).unwrap(), "out.png");
+        assert!(expand_template("{stem}.png", None).is_err());
+    }
+
+    #[test]
+    fn test_undo_limit(){
+        // room for two 1x1 rgb images
+        let mut b = Buffers::new(6);
+        let i = b.push(img(1));
+        b.replace(i, img(1));
+        b.replace(i, img(1));
+        b.replace(i, img(1));
+        assert_eq!(b.history_len(i), 2);
+        // too large to keep at all
+        let j = b.push(img(3));
+        b.replace(j, img(1));
+        assert_eq!(b.history_len(j), 0);
+    }
+}
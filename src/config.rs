@@ -0,0 +1,205 @@
+use serde::Deserialize;
+
+use image::imageops::FilterType;
+
+use std::path::{ Path, PathBuf };
+
+/// Settings from `~/.config/editimg/config.toml`, everything is optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub window: WindowConfig,
+    pub scripts: ScriptsConfig,
+    pub defaults: Defaults,
+    pub colours: Colours,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub width: u32,
+    pub height: u32,
+    pub title: String,
+    /// WM_CLASS on X11, for TWM rules
+    pub class: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    /// Searched in order for commands and lib.rhai.rs, before `~/.config/editimg`.
+    pub dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Defaults {
+    /// Used by the resize functions when the filter is "" or "default".
+    pub resize_filter: String,
+    pub jpeg_quality: u8,
+    /// Maximum amount of undo history kept per buffer, in megabytes.
+    pub undo_limit: usize,
+}
+
+/// RGB colours, the overlay panel also has an alpha.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Colours {
+    pub background: [u8; 3],
+    pub overlay: [u8; 4],
+    pub overlay_text: [u8; 3],
+    pub status: [u8; 3],
+    pub status_text: [u8; 3],
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 512,
+            height: 512,
+            title: "editimg".to_string(),
+            class: "editimg".to_string(),
+        }
+    }
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Self {
+            resize_filter: "triangle".to_string(),
+            jpeg_quality: 90,
+            undo_limit: 512,
+        }
+    }
+}
+
+impl Defaults {
+    /// The undo limit in bytes, a limit too large to count in bytes is no limit.
+    pub fn undo_bytes(&self) -> usize {
+        self.undo_limit.saturating_mul(1024 * 1024)
+    }
+
+    /// Whether the values from the config file and the command line make sense.
+    pub fn check(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.jpeg_quality) {
+            return Err(format!(
+                "defaults.jpeg_quality must be between 1 and 100, got {}", self.jpeg_quality
+            ));
+        }
+        if resize_filter(&self.resize_filter).is_none() {
+            let f = &self.resize_filter;
+            return Err(format!("defaults.resize_filter: unknown filter \"{f}\""));
+        }
+        Ok(())
+    }
+}
+
+/// The filter with this name, in any case.
+pub fn resize_filter(name: &str) -> Option<FilterType> {
+    match name.to_lowercase().as_ref() {
+        "nearest" => Some(FilterType::Nearest),
+        "triangle" => Some(FilterType::Triangle),
+        "catmullrom" => Some(FilterType::CatmullRom),
+        "catmull-rom" => Some(FilterType::CatmullRom),
+        "gaussian" => Some(FilterType::Gaussian),
+        "lanczos3" => Some(FilterType::Lanczos3),
+        "lanczos" => Some(FilterType::Lanczos3),
+        _ => None,
+    }
+}
+
+impl Default for Colours {
+    fn default() -> Self {
+        Self {
+            background: [0, 0, 0],
+            overlay: [0, 0, 0, 180],
+            overlay_text: [230, 230, 230],
+            status: [24, 24, 24],
+            status_text: [230, 230, 230],
+        }
+    }
+}
+
+impl Config {
+    /// A missing file is the default config, a broken one is an error.
+    pub fn load(path: &Path, home: &Path) -> Result<Self, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("could not read {}: {e}", path.display())),
+        };
+        let mut config = Self::parse(&text)
+            .map_err(|e| format!("could not parse {}: {e}", path.display()))?;
+        for dir in &mut config.scripts.dirs {
+            *dir = expand_home(dir, home);
+        }
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.defaults.check()?;
+        Ok(config)
+    }
+}
+
+/// Replace a leading `~` with the home directory.
+pub fn expand_home(path: &Path, home: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => home.join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+
+    #[test]
+    fn test_parse(){
+        let c = Config::parse("").unwrap();
+        assert_eq!(c.window.width, 512);
+        assert_eq!(c.defaults.resize_filter, "triangle");
+
+        let c = Config::parse(r#"
+            [window]
+            width = 800
+            class = "float"
+
+            [defaults]
+            jpeg_quality = 75
+
+            [colours]
+            overlay = [10, 20, 30, 40]
+        "#).unwrap();
+        assert_eq!((c.window.width, c.window.height), (800, 512));
+        assert_eq!(c.window.class, "float");
+        assert_eq!(c.defaults.jpeg_quality, 75);
+        assert_eq!(c.defaults.undo_limit, 512);
+        assert_eq!(c.colours.overlay, [10, 20, 30, 40]);
+        assert_eq!(c.colours.background, [0, 0, 0]);
+
+        assert!(Config::parse("[window]\nwidht = 800").is_err());
+        assert!(Config::parse("[defaults]\njpeg_quality = 0").is_err());
+        assert!(Config::parse("[defaults]\nresize_filter = \"Lanczos3\"").is_ok());
+        assert!(Config::parse("[defaults]\nresize_filter = \"cubic\"").is_err());
+        assert!(Config::parse("[defaults]\nresize_filter = \"default\"").is_err());
+    }
+
+    #[test]
+    fn test_undo_bytes(){
+        let mut d = Defaults::default();
+        assert_eq!(d.undo_bytes(), 512 * 1024 * 1024);
+        d.undo_limit = usize::MAX;
+        assert_eq!(d.undo_bytes(), usize::MAX);
+    }
+
+    #[test]
+    fn test_expand_home(){
+        let home = Path::new("/home/me");
+        assert_eq!(expand_home(Path::new("~/scripts"), home), Path::new("/home/me/scripts"));
+        assert_eq!(expand_home(Path::new("/etc/editimg"), home), Path::new("/etc/editimg"));
+        assert_eq!(expand_home(Path::new("./~"), home), Path::new("./~"));
+    }
+}
@@ -0,0 +1,18 @@
+/// The scripts of the repository, used when a command is not found on disk
+/// and installed by `editimg init`.
+pub const SCRIPTS: &[(&str, &str)] = &[
+    ("lib", include_str!("../scripts/lib.rhai.rs")),
+    ("crop", include_str!("../scripts/crop.rhai.rs")),
+    ("dcrop", include_str!("../scripts/dcrop.rhai.rs")),
+    ("fx", include_str!("../scripts/fx.rhai.rs")),
+    ("int", include_str!("../scripts/int.rhai.rs")),
+    ("repl", include_str!("../scripts/repl.rhai.rs")),
+    ("resize_web", include_str!("../scripts/resize_web.rhai.rs")),
+    ("scrop", include_str!("../scripts/scrop.rhai.rs")),
+    ("stack", include_str!("../scripts/stack.rhai.rs")),
+    ("trans", include_str!("../scripts/trans.rhai.rs")),
+];
+
+pub fn script(name: &str) -> Option<&'static str> {
+    SCRIPTS.iter().find(|(n, _)| *n == name).map(|(_, code)| *code)
+}
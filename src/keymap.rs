@@ -0,0 +1,175 @@
+use crate::scripting::Input;
+
+/// Bindings in this mode work in every mode.
+pub const ANY_MODE: &str = "*";
+
+/// Key bindings per mode, the closures themselves live on the Rhai side under the id.
+pub struct Keymap {
+    bindings: Vec<Binding>,
+    mode: String,
+    next_id: i64,
+}
+
+struct Binding {
+    mode: String,
+    key: String,
+    description: String,
+    id: i64,
+}
+
+impl Keymap {
+    pub fn new() -> Self {
+        Self {
+            bindings: Vec::new(),
+            mode: "normal".to_string(),
+            next_id: 0,
+        }
+    }
+
+    /// Returns the id of the binding, binding a key again replaces the old binding.
+    pub fn bind(&mut self, mode: &str, key: &str, description: &str) -> i64 {
+        let key = normalize(key);
+        self.bindings.retain(|b| b.mode != mode || b.key != key);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.bindings.push(Binding {
+            mode: mode.to_string(),
+            key,
+            description: description.to_string(),
+            id,
+        });
+        id
+    }
+
+    pub fn enter(&mut self, mode: &str) {
+        self.mode = mode.to_string();
+    }
+
+    pub fn mode(&self) -> &str {
+        &self.mode
+    }
+
+    /// Bindings of the current mode go before the ones of every mode.
+    pub fn lookup(&self, i: &Input) -> Option<i64> {
+        let key = key_name(i);
+        self.bindings.iter()
+            .filter(|b| b.key == key)
+            .find(|b| b.mode == self.mode)
+            .or_else(|| self.bindings.iter().find(|b| b.key == key && b.mode == ANY_MODE))
+            .map(|b| b.id)
+    }
+
+    /// The key, description and id of the bindings that work in the current mode.
+    pub fn active(&self) -> Vec<(String, String, i64)> {
+        self.active_bindings().iter()
+            .map(|b| (b.key.clone(), b.description.clone(), b.id))
+            .collect()
+    }
+
+    /// Lines listing the bindings that work in the current mode.
+    pub fn help(&self) -> Vec<String> {
+        let bindings = self.active_bindings();
+        let w = bindings.iter().map(|b| b.key.chars().count()).max().unwrap_or(0);
+        let mut lines = vec![format!("[{}]", self.mode)];
+        lines.extend(bindings.iter().map(|b| format!("{:<w$}  {}", b.key, b.description)));
+        lines
+    }
+
+    fn active_bindings(&self) -> Vec<&Binding> {
+        let mut bindings = self.bindings.iter()
+            .filter(|b| b.mode == self.mode)
+            .collect::<Vec<_>>();
+        if self.mode != ANY_MODE {
+            // unless the mode binds the same key itself
+            let shadowed = |b: &Binding| bindings.iter().any(|o| o.key == b.key);
+            let any = self.bindings.iter()
+                .filter(|b| b.mode == ANY_MODE && !shadowed(b))
+                .collect::<Vec<_>>();
+            bindings.extend(any);
+        }
+        bindings
+    }
+}
+
+/// Keys that only modify other keys, these are never bound on their own.
+pub fn is_modifier(key: &str) -> bool {
+    matches!(key,
+        "lshift" | "rshift" | "lctrl" | "rctrl" | "lalt" | "ralt" | "lgui" | "rgui"
+        | "capslock" | "numlockclear" | "mode"
+    )
+}
+
+/// Name of a key press: "C-", "A-" and "S-" for control, alt and shift, then the key.
+pub fn key_name(i: &Input) -> String {
+    let mut name = String::new();
+    if i.control { name.push_str("C-"); }
+    if i.alt { name.push_str("A-"); }
+    if i.shift { name.push_str("S-"); }
+    name.push_str(&i.key);
+    name
+}
+
+/// Bring a key as written in a script into the form of key_name,
+/// a single upper case letter is that letter with shift.
+pub fn normalize(spec: &str) -> String {
+    let (mut control, mut alt, mut shift) = (false, false, false);
+    let mut key = spec;
+    while key.len() > 2 {
+        match &key[..2] {
+            "C-" | "c-" => control = true,
+            "A-" | "a-" => alt = true,
+            "S-" | "s-" => shift = true,
+            _ => break,
+        }
+        key = &key[2..];
+    }
+    let mut chars = key.chars();
+    let key = match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_uppercase() => {
+            shift = true;
+            c.to_ascii_lowercase().to_string()
+        },
+        _ => key.to_lowercase(),
+    };
+    let mut name = String::new();
+    if control { name.push_str("C-"); }
+    if alt { name.push_str("A-"); }
+    if shift { name.push_str("S-"); }
+    name.push_str(&key);
+    name
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use sdl2::keyboard::Mod;
+
+    #[test]
+    fn test_normalize(){
+        assert_eq!(normalize("g"), "g");
+        assert_eq!(normalize("G"), "S-g");
+        assert_eq!(normalize("C-Z"), "C-S-z");
+        assert_eq!(normalize("S-C-z"), "C-S-z");
+        assert_eq!(normalize("Return"), "return");
+        assert_eq!(normalize("C-"), "c-");
+        assert_eq!(key_name(&Input::key("z".to_string(), Mod::LCTRLMOD | Mod::LSHIFTMOD)), "C-S-z");
+    }
+
+    #[test]
+    fn test_lookup(){
+        let mut k = Keymap::new();
+        let undo = k.bind(ANY_MODE, "C-z", "undo");
+        let invert = k.bind("fx", "i", "invert");
+        let fx = k.bind("normal", "f", "fx");
+        let key = |name: &str| Input::key(name.to_string(), Mod::NOMOD);
+        assert_eq!(k.lookup(&key("f")), Some(fx));
+        assert_eq!(k.lookup(&key("i")), None);
+        assert_eq!(k.lookup(&Input::key("z".to_string(), Mod::LCTRLMOD)), Some(undo));
+        k.enter("fx");
+        assert_eq!(k.lookup(&key("i")), Some(invert));
+        assert_eq!(k.help(), vec!["[fx]", "i    invert", "C-z  undo"]);
+        let again = k.bind("fx", "i", "invert again");
+        assert_eq!(k.lookup(&key("i")), Some(again));
+    }
+}
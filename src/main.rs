@@ -1,14 +1,29 @@
 mod window;
 mod timer;
 mod scripting;
+mod repl;
+mod buffers;
+mod prompt;
+mod font;
+mod keymap;
+mod palette;
+mod config;
+mod embedded;
+mod save;
+mod batch;
 
 use crate::{
-    window::EIWindow,
+    window::{ EIWindow, RenderFilter },
     timer::Timer,
     scripting::*,
+    buffers::{ Buffers, Origin, Metadata, expand_template },
+    save::SaveOptions,
+    config::{ Config, Defaults, resize_filter },
+    batch::InOrder,
 };
 
 use sdl2::{
+    EventPump,
     event::{ Event, WindowEvent },
     keyboard::Keycode,
 };
@@ -17,123 +32,358 @@ use simpleio as sio;
 
 use image::{
     ImageReader as IR,
+    ImageDecoder,
     DynamicImage,
     GenericImage,
     imageops::FilterType,
     RgbImage,
 };
 
-use clap::Parser;
+use clap::{ Parser, Subcommand };
 
 use std::{
-    collections::VecDeque,
-    sync::mpsc,
-    path::PathBuf,
+    collections::{ VecDeque, BTreeSet, HashMap },
+    sync::{ mpsc, atomic::{ AtomicUsize, Ordering } },
+    thread::JoinHandle,
+    path::{ Path, PathBuf },
 };
 
 use rhai::{
+    AST,
+    Engine,
     Module,
     Scope,
+    module_resolvers::{ FileModuleResolver, ModuleResolversCollection },
 };
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(subcommand_negates_reqs = true)]
 struct Args {
-    command: String,
+    #[clap(subcommand)]
+    action: Option<Action>,
+    #[clap(required_unless_present = "eval")]
+    command: Option<String>,
+    /// Run this Rhai code instead of a command, all other arguments are images
+    #[clap(short, long, value_name = "SCRIPT")]
+    eval: Option<String>,
+    /// Run without a window: input events terminate and drawing is skipped
+    #[clap(long)]
+    headless: bool,
+    /// Start a Rhai REPL in the terminal once the command has run
+    #[clap(short, long)]
+    interactive: bool,
+    /// Run the command headless once per image, with only that image loaded
+    #[clap(long, conflicts_with = "interactive")]
+    batch: bool,
+    /// Amount of images a batch works on at once
+    #[clap(short, long, default_value_t = 1, requires = "batch",
+        value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+    /// Maximum amount of undo history kept per buffer, in megabytes [config default: 512]
+    #[clap(long)]
+    undo_limit: Option<usize>,
+    /// Config file to use instead of ~/.config/editimg/config.toml
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Directory searched for commands before the configured ones, can be given more than once
+    #[clap(long)]
+    script_dir: Vec<PathBuf>,
+    /// Initial window width
+    #[clap(long)]
+    width: Option<u32>,
+    /// Initial window height
+    #[clap(long)]
+    height: Option<u32>,
+    /// Window title
+    #[clap(long)]
+    title: Option<String>,
+    /// Window class, for window manager rules
+    #[clap(long)]
+    class: Option<String>,
+    /// Filter used when a resize function gets "" or "default" as filter
+    #[clap(long)]
+    resize_filter: Option<String>,
+    /// Quality of saved JPEG images, 1 to 100
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: Option<u8>,
+    /// Value for arg(key, default) in the script, can be given more than once
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_set)]
+    set: Vec<(String, String)>,
+    /// Output for the script, arg("output", default) in the script
+    #[clap(short, long)]
+    output: Option<String>,
     #[clap(num_args = 1..)]
     input: Option<Vec<PathBuf>>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Install the bundled scripts into ~/.config/editimg, existing files are kept
+    Init,
+    /// List the available commands and where they are loaded from
+    List,
+}
+
 pub fn main() -> Result<(), String> {
     let args = Args::parse();
 
-    let (
-        host_portals,
-        RhaiPortals {
-            from_rhai, mut to_rhai,
-        },
-    ) = create_channels();
-    let (to_host, from_thread) = mpsc::channel();
+    let home = sio::get_home().vital("Editimg: could not get home directory");
+    let cdir = home.join(".config/editimg");
+    let cpath = args.config.clone().unwrap_or_else(|| cdir.join("config.toml"));
+    let config = apply_args(Config::load(&cpath, &home).vital("Editimg: bad config"), &args);
+    config.defaults.check().vital("Editimg: bad config");
 
-    let mut lpath = sio::get_home().vital("Editimg: could not get home directory");
-    let mut rpath = lpath.clone();
-    lpath.push(".config/editimg/lib.rhai.rs");
-    rpath.push(".config/editimg");
-    rpath.push(args.command);
-    rpath.set_extension("rhai.rs");
+    let xdg_data_dirs = std::env::var("XDG_DATA_DIRS").unwrap_or_default();
+    let dirs = script_dirs(&args.script_dir, &config.scripts.dirs, &cdir, &xdg_data_dirs);
+    match args.action {
+        Some(Action::Init) => return init(&cdir),
+        Some(Action::List) => return list(&dirs),
+        None => {},
+    }
+
+    let hpath = cdir.join("history");
+    let (_, lib_code) = load_script(&dirs, "lib").vital("Editimg: could not load library");
+    let (rpath, run_code, imgs) = if let Some(code) = &args.eval {
+        // there is no command, so the first argument is an image as well
+        let imgs = args.command.iter().map(PathBuf::from)
+            .chain(args.input.iter().flatten().cloned())
+            .collect::<Vec<_>>();
+        ("inline".to_string(), code.clone(), imgs)
+    } else {
+        let command = args.command.clone().unwrap_or_default();
+        let (rpath, code) = load_script(&dirs, &command).vital("Editimg: could not load command");
+        (rpath, code, args.input.clone().unwrap_or_default())
+    };
     println!("{:?}", rpath);
-    let lib_code = sio::read_file_into_string(&lpath).vital("Editimg: could not load library");
-    let run_code = sio::read_file_into_string(&rpath).vital("Editimg: could not load command");
+    let mut cli_args = args.set.iter().cloned().collect::<HashMap<_, _>>();
+    if let Some(output) = &args.output {
+        cli_args.insert("output".to_string(), output.clone());
+    }
+    if imgs.is_empty() {
+        return Err("Editimg: input images list is empty".to_string());
+    }
+    let script = Script {
+        dirs, lib_code, run_code, cli_args, hpath,
+        interactive: args.interactive,
+        echo: !args.batch,
+    };
+    if args.batch {
+        return batch(&script, &imgs, &config, args.jobs as usize);
+    }
 
-    std::thread::spawn(move || {
-        let mut engine = construct_rhai_engine(host_portals);
-        match engine.compile(&lib_code) {
-            Ok(ast) => {
-                match Module::eval_ast_as_new(Scope::new(), &ast, &engine) {
-                    Ok(module) => {
-                        engine.register_global_module(module.into());
-                    },
-                    Err(e) => {
-                        to_host.send(Some(e.to_string()))
-                            .expect("Editimg: compilation verification send error");
-                    },
-                }
-            },
-            Err(e) => {
-                to_host.send(Some(e.to_string()))
-                    .expect("Editimg: compilation verification send error");
-            },
-        }
-        match engine.compile(&run_code) {
+    let input_paths = imgs.iter().map(|p| p.display().to_string()).collect();
+    let (portals, handle) = spawn_script(&script, input_paths)?;
+
+    println!("Starting main loop...");
+
+    let timer = Timer::new();
+    let gui = if args.headless {
+        None
+    } else {
+        Some(EIWindow::create(&timer, &config)?)
+    };
+
+    let mut images = Buffers::new(config.defaults.undo_bytes());
+    for img in imgs {
+        let (image, origin) = load_image(&img)?;
+        println!("Image: {:?}ms", timer.elapsed());
+        let i = images.push(image);
+        images.set_origin(i, Some(origin));
+    }
+
+    let headless = gui.is_none();
+    let killed = run_host(gui, timer, images, portals, &config, None)?;
+    // headless the host only stops once the script is done, so its error is the run's error;
+    // with a window the user may close it in the middle of a call
+    if headless && !killed {
+        handle.join().map_err(|_| "the script panicked".to_string())??;
+    }
+    println!("Editimg: finished.");
+    Ok(())
+}
+
+/// Everything needed to start the command, again for every image of a batch.
+#[derive(Clone)]
+struct Script {
+    dirs: Vec<PathBuf>,
+    lib_code: String,
+    run_code: String,
+    cli_args: HashMap<String, String>,
+    hpath: PathBuf,
+    interactive: bool,
+    /// Print what the script prints and its errors straight away.
+    echo: bool,
+}
+
+/// Compile the library and the command and run them on a thread of their own, the requests of
+/// the script come in through the portals. The thread ends with the error of the script if any.
+fn spawn_script(script: &Script, input_paths: Vec<String>)
+    -> Result<(RhaiPortals, JoinHandle<Result<(), String>>), String>
+{
+    let (host_portals, rhai_portals) = create_channels();
+    let (to_host, from_thread) = mpsc::channel();
+    let Script { dirs, lib_code, run_code, cli_args, hpath, interactive, echo } = script.clone();
+
+    let handle = std::thread::spawn(move || {
+        let repl_portals = host_portals.clone();
+        let mut engine = construct_rhai_engine(host_portals, echo);
+        engine.set_module_resolver(module_resolver(&dirs));
+        register_cli(&mut engine, cli_args, input_paths);
+        match compile(&mut engine, &lib_code, &run_code) {
             Ok(ast) => {
                 to_host.send(None).expect("Editimg: compilation verification send error");
-                engine.run_ast(&ast).expect("Editimg: rhai run error");
+                let mut scope = Scope::new();
+                let res = engine.run_ast_with_scope(&mut scope, &ast).map_err(|e| {
+                    if echo { println!("Rhai error: {e}"); }
+                    e.to_string()
+                });
+                if interactive {
+                    repl::run(&engine, &mut scope, &ast, &hpath);
+                    let _ = repl_portals.call::<()>(HostMsg::Kill);
+                }
+                res
             },
             Err(e) => {
-                to_host.send(Some(e.to_string()))
+                to_host.send(Some(e.clone()))
                     .expect("Editimg: compilation verification send error");
+                Err(e)
             },
         }
     });
 
     if let Some(e) = from_thread.recv().vital("Editimg: compilation verification receive error") {
-        println!("Rhai Compile error: {}", e);
-        return Err("Editimg: could not compile, aborting".to_string());
+        return Err(compile_error(&e));
     }
+    Ok((rhai_portals, handle))
+}
 
-    println!("Starting main loop...");
+/// Compile the library into the engine, then the command.
+fn compile(engine: &mut Engine, lib_code: &str, run_code: &str) -> Result<AST, String> {
+    let lib = engine.compile(lib_code).map_err(|e| e.to_string()).and_then(|ast| {
+        Module::eval_ast_as_new(Scope::new(), &ast, engine).map_err(|e| e.to_string())
+    })?;
+    engine.register_global_module(lib.into());
+    engine.compile(run_code).map_err(|e| e.to_string())
+}
+
+/// Compile the script without running it, so a batch stops before it starts.
+fn check_compiles(script: &Script) -> Result<(), String> {
+    let (host_portals, _) = create_channels();
+    let mut engine = construct_rhai_engine(host_portals, false);
+    engine.set_module_resolver(module_resolver(&script.dirs));
+    register_cli(&mut engine, script.cli_args.clone(), Vec::new());
+    compile(&mut engine, &script.lib_code, &script.run_code)
+        .map(|_| ())
+        .map_err(|e| compile_error(&e))
+}
 
-    let mut timer = Timer::new();
-    let (mut window, mut event_pump) = EIWindow::create(&timer)?;
+fn compile_error(e: &str) -> String {
+    println!("Rhai Compile error: {}", e);
+    "Editimg: could not compile, aborting".to_string()
+}
 
-    let imgs = if let Some(imgs) = args.input {
-        if imgs.is_empty() {
-            return Err("Editimg: input images list is empty".to_string());
+/// Run the command on every image, headless and with only that image loaded, on `jobs` images
+/// at once. What the script prints is kept per image and shown in the order of the images,
+/// whichever finishes first.
+fn batch(script: &Script, imgs: &[PathBuf], config: &Config, jobs: usize) -> Result<(), String> {
+    check_compiles(script)?;
+    let next = AtomicUsize::new(0);
+    let (to_main, done) = mpsc::channel();
+    let mut failed = 0;
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(imgs.len()) {
+            let (to_main, next) = (to_main.clone(), &next);
+            scope.spawn(move || {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(img) = imgs.get(i) else { break };
+                    let mut log = String::new();
+                    let res = batch_image(script, img, config, &mut log);
+                    let stop = res.is_err();
+                    if to_main.send((i, res, log)).is_err() || stop {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(to_main);
+        let mut order = InOrder::default();
+        for (i, res, log) in done {
+            let res = res.inspect_err(|_| {
+                // nothing will compile, let the other workers stop as well
+                next.store(imgs.len(), Ordering::Relaxed);
+            })?;
+            for (i, (res, log)) in order.push(i, (res, log)) {
+                match res {
+                    Ok(()) => println!("ok      {}", imgs[i].display()),
+                    Err(e) => {
+                        failed += 1;
+                        println!("failed  {}: {e}", imgs[i].display());
+                    },
+                }
+                for line in log.lines() {
+                    println!("        {line}");
+                }
+            }
         }
-        imgs
+        Ok::<(), String>(())
+    })?;
+    println!("Editimg: {} of {} images done, {failed} failed", imgs.len() - failed, imgs.len());
+    if failed == 0 {
+        Ok(())
     } else {
-        return Err("Editimg: could not parse input images".to_string());
+        Err(format!("Editimg: {failed} images failed"))
+    }
+}
+
+/// Run the command on one image of a batch, the outer error ends the whole batch.
+fn batch_image(script: &Script, img: &Path, config: &Config, log: &mut String)
+    -> Result<Result<(), String>, String>
+{
+    let (image, origin) = match load_image(img) {
+        Ok(loaded) => loaded,
+        Err(e) => return Ok(Err(e)),
     };
+    // checked before the batch, but the library may still fail on this image
+    let (portals, handle) = spawn_script(script, vec![img.display().to_string()])?;
+    let mut images = Buffers::new(config.defaults.undo_bytes());
+    let i = images.push(image);
+    images.set_origin(i, Some(origin));
+    Ok(run_host(None, Timer::new(), images, portals, config, Some(log)).and_then(|killed| {
+        let res = handle.join().map_err(|_| "the script panicked".to_string())?;
+        // once killed the host is gone, so whatever the script does next fails
+        if killed { Ok(()) } else { res }
+    }))
+}
 
-    let mut images = Vec::new();
-    for img in imgs {
-        let image = IR::open(img)
-            .map_err(|e| e.to_string())?
-            .decode()
-            .map_err(|e| e.to_string())?;
-        println!("Image: {:?}ms", timer.elapsed());
-        images.push(image);
+/// Answer the requests of the script until it is done or editimg is closed, returns whether
+/// the script called kill. Without a window everything is done as soon as the script asks.
+/// With a log what the script prints and the saves are kept there instead of printed.
+fn run_host(
+    mut gui: Option<(EIWindow, EventPump)>, mut timer: Timer, mut images: Buffers,
+    RhaiPortals { from_rhai, mut to_rhai }: RhaiPortals, config: &Config,
+    mut log: Option<&mut String>,
+) -> Result<bool, String> {
+    if let Some((window, _)) = &mut gui {
+        window.set_texture(&images[0], &mut timer)?;
     }
 
-    window.set_texture(&images[0], &mut timer)?;
-
     let mut inputs = VecDeque::new();
     let mut polls = VecDeque::new();
     let mut rects_uv = Vec::new();
     let mut rects_xy = Vec::new();
     let mut show = 0;
-    let mut last: Option<HostMsg> = None;
+    let mut last: Option<Request> = None;
+    let mut drag_sel: Option<RectXY> = None;
+    let mut prompt: Option<prompt::Prompt> = None;
+    let mut keymap = keymap::Keymap::new();
+    let mut keymap_help = false;
+    let mut palette: Option<palette::Palette> = None;
     let mut repeated = false;
+    let mut killed = false;
+    // the input delivered last was a key that is followed by its text
+    let mut paired_text = false;
 
     loop {
         let mut drawn = false;
@@ -142,29 +392,56 @@ pub fn main() -> Result<(), String> {
         let mut repeat = false;
         let mut set_last = true;
 
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                    die = true;
-                    break;
-                },
-                Event::Window{ win_event: WindowEvent::Resized(winw, winh), .. } => {
-                    println!("Resized: ({winw}, {winh})");
-                    let winw = winw.max(0).unsigned_abs();
-                    let winh = winh.max(0).unsigned_abs();
-                    timer.checkpoint();
-                    window.resize_redraw(winw, winh)?;
-                    println!("Resizing: {:?}ms", timer.elapsed());
-                },
-                Event::KeyDown { keycode: Some(kc), keymod, .. } => {
-                    inputs.push_back(Input::key(format!("{:?}", kc).to_lowercase(), keymod));
-                },
-                Event::MouseButtonDown{ mouse_btn, clicks: 1, x, y, .. } => {
-                    let button = format!("{:?}", mouse_btn).to_lowercase();
-                    inputs.push_back(Input::click(window.screen_to_click(x, y), button));
-                },
-                _ => {}
+        // escape cancels a prompt or the palette instead of closing editimg
+        let prompting = prompt.is_some() || palette.is_some();
+        if let Some((window, event_pump)) = &mut gui {
+            for event in event_pump.poll_iter() {
+                match event {
+                    Event::Quit { .. } => {
+                        die = true;
+                        break;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Escape), .. } if !prompting => {
+                        die = true;
+                        break;
+                    },
+                    Event::Window{ win_event: WindowEvent::Resized(winw, winh), .. } => {
+                        println!("Resized: ({winw}, {winh})");
+                        let winw = winw.max(0).unsigned_abs();
+                        let winh = winh.max(0).unsigned_abs();
+                        timer.checkpoint();
+                        window.resize_redraw(winw, winh)?;
+                        println!("Resizing: {:?}ms", timer.elapsed());
+                    },
+                    Event::KeyDown { keycode: Some(kc), keymod, .. } => {
+                        inputs.push_back(Input::key(format!("{:?}", kc).to_lowercase(), keymod));
+                    },
+                    Event::TextInput { text, .. } => {
+                        inputs.push_back(Input::text(text));
+                    },
+                    Event::MouseButtonDown{ mouse_btn, clicks: 1, x, y, .. } => {
+                        let button = format!("{:?}", mouse_btn).to_lowercase();
+                        inputs.push_back(Input::click(window.screen_to_click(x, y), button));
+                    },
+                    Event::MouseButtonUp{ mouse_btn, x, y, .. } => {
+                        let button = format!("{:?}", mouse_btn).to_lowercase();
+                        inputs.push_back(Input::release(window.screen_to_click(x, y), button));
+                    },
+                    // plain mouse movement is not interesting, only dragging is
+                    Event::MouseMotion{ mousestate, x, y, .. }
+                        if mousestate.left() || mousestate.middle() || mousestate.right() => {
+                        inputs.push_back(Input::drag(window.screen_to_click(x, y), mousestate));
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        if gui.is_none() && polls.is_empty() {
+            // headless: nothing to render, so wait for the script; once it is done we are too
+            match from_rhai.recv() {
+                Ok(rhai_call) => polls.push_back(rhai_call),
+                Err(_) => die = true,
             }
         }
 
@@ -174,208 +451,387 @@ pub fn main() -> Result<(), String> {
 
         let pt = polls.iter().next();
         let pt1 = pt.cloned();
-        if let Some(pt) = pt {
+        if let Some(Request { id, msg: pt, wait }) = pt {
             use HostMsg::*;
-            let mut pop = true;
-            match pt {
+            // None when the request can not be answered yet
+            let reply = match pt {
                 Kill => {
                     die = true;
+                    killed = true;
+                    Some(Ok(Value::Unit))
                 },
                 GetInputEvent => {
+                    set_last = false;
                     if let Some(i) = inputs.pop_front() {
-                        to_rhai.send(RhaiMsg::Input(i)).map_err(|_| "Editimg: cannot push input")?;
+                        paired_text = i.key != "text" && !i.is_click && !i.is_drag
+                            && !i.is_release && inputs.front().is_some_and(|n| n.key == "text");
+                        Some(Ok(Value::Input(i)))
+                    } else if gui.is_none() {
+                        Some(Ok(Value::Input(Input::termination())))
                     } else {
-                        pop = false;
+                        None
                     }
-                    set_last = false;
                 },
                 GetWH(src) => {
-                    let i = img_index(src, &images);
-                    let w = images[i].width();
-                    let h = images[i].height();
-                    to_rhai.send(RhaiMsg::Int(w as i64)).map_err(|_| "Editimg: cannot push width")?;
-                    to_rhai.send(RhaiMsg::Int(h as i64)).map_err(|_| "Editimg: cannot push height")?;
                     set_last = false;
+                    img_index("get_wh", src, &images).map(|i| Value::WH(WH {
+                        w: images[i].width() as i64,
+                        h: images[i].height() as i64,
+                    })).into()
                 },
                 GetBuffersLen => {
-                    to_rhai.send(RhaiMsg::Int(images.len() as i64))
-                        .map_err(|_| "Editimg: cannot push buffers length")?;
+                    Some(Ok(Value::Int(images.len() as i64)))
                 },
                 ClearRects => {
-                    window.clear_rects();
-                    window.redraw_texture()?;
-                    drawn = true;
+                    if let Some((window, _)) = &mut gui {
+                        window.clear_rects();
+                        window.redraw_texture()?;
+                        drawn = true;
+                    }
+                    Some(Ok(Value::Unit))
                 },
                 DrawRectUV(r) => {
                     rects_uv.push(r.clone());
+                    Some(Ok(Value::Unit))
                 },
                 DrawRectXY(r) => {
                     rects_xy.push(r.clone());
+                    Some(Ok(Value::Unit))
                 },
-                Crop(src, dst, px, py, qx, qy) => {
-                    let s = img_index(src, &images);
-                    if px == qx || py == qy {
-                        println!("Select at an area of least 1x1 pixels!");
-                        send_int(&mut to_rhai, s as i64, "Editimg: cannot push crop src", !repeated)?;
-                    } else {
-                        let (px, py, qx, qy) = img_crop(*px, *py, *qx, *qy);
-                        let img = images[s].crop(px, py, qx - px, qy - py);
-                        let d = put_img(dst, img, &mut images, &mut redraw, show);
-                        send_int(&mut to_rhai, d, "Editimg: cannot push crop dst", !repeated)?;
+                Save(..) | SaveWith(..) | SaveMaxBytes(..) => {
+                    let res = image_op(pt, &mut images, &mut redraw, show, &config.defaults);
+                    match (&res, &mut log) {
+                        (Ok(_), Some(log)) => log.push_str("saved!\n"),
+                        (Ok(_), None) => println!("saved!"),
+                        _ => {},
                     }
-                },
-                Save(source, path) => {
-                    let s = img_index(source, &images);
-                    match images[s].save(path) {
-                        Ok(_) => println!("saved!"),
-                        Err(e) => println!("Failed: {:#?}", e),
+                    if let (Ok(_), Some((window, _))) = (&res, &mut gui) {
+                        window.push_message("saved!\n");
+                        window.redraw_all()?;
+                        drawn = true;
                     }
+                    Some(res)
+                },
+                Crop(..) | Load(..) | FlipH(..) | FlipV(..) | Rot90(..) | Rot180(..)
+                | Rot270(..) | Invert(..) | Grayscale(..) | Blur(..) | Unsharpen(..)
+                | Filter3x3(..) | AdjustContrast(..) | Brighten(..) | Huerotate(..) | Resize(..)
+                | ResizeExact(..) | ResizeFill(..) | Thumbnail(..) | ThumbnailExact(..)
+                | Create(..) | Copy(..) | Undo(..) | Redo(..) => {
+                    Some(image_op(pt, &mut images, &mut redraw, show, &config.defaults))
                 },
-                FlipH(src, dst) => {
-                    img_action(src, dst, &mut images, &mut redraw, show, &mut to_rhai,
-                        "fliph dst", DynamicImage::fliph, !repeated)?;
-                },
-                FlipV(src, dst) => {
-                    img_action(src, dst, &mut images, &mut redraw, show, &mut to_rhai,
-                        "flipv dst", DynamicImage::flipv, !repeated)?;
-                },
-                Rot90(src, dst) => {
-                    img_action(src, dst, &mut images, &mut redraw, show, &mut to_rhai,
-                        "rotate90 dst", DynamicImage::rotate90, !repeated)?;
-                },
-                Rot180(src, dst) => {
-                    img_action(src, dst, &mut images, &mut redraw, show, &mut to_rhai,
-                        "rotate180 dst", DynamicImage::rotate180, !repeated)?;
-                },
-                Rot270(src, dst) => {
-                    img_action(src, dst, &mut images, &mut redraw, show, &mut to_rhai,
-                        "rotate270 dst", DynamicImage::rotate270, !repeated)?;
-                },
-                Invert(src, dst) => {
-                    let s = img_index(src, &images);
-                    let mut r = images[s].clone();
-                    r.invert();
-                    let d = put_img(dst, r, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push invert dst", !repeated)?;
-                },
-                Grayscale(src, dst) => {
-                    img_action(src, dst, &mut images, &mut redraw, show, &mut to_rhai,
-                        "grayscale", DynamicImage::grayscale, !repeated)?;
-                },
-                Blur(src, dst, sigma) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].blur(*sigma as f32);
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push blur dst", !repeated)?;
-                },
-                Unsharpen(src, dst, sigma, threshold) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].unsharpen(*sigma as f32, *threshold as i32);
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push unsharpen dst", !repeated)?;
-                },
-                Filter3x3(src, dst, fltr) => {
-                    let s = img_index(src, &images);
-                    let f = fltr.iter().map(|v| *v as f32).collect::<Vec<_>>();
-                    let img = images[s].filter3x3(&f);
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push filter dst", !repeated)?;
-                },
-                AdjustContrast(src, dst, c) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].adjust_contrast(*c as f32);
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push contrast dst", !repeated)?;
-                },
-                Brighten(src, dst, v) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].brighten(*v as i32);
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push brighten dst", !repeated)?;
-                },
-                Huerotate(src, dst, v) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].huerotate(*v as i32);
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push huerotate dst", !repeated)?;
-                },
-                Resize(src, dst, w, h, ft) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].resize(clamp(w), clamp(h), filtertype(ft));
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push resize dst", !repeated)?;
-                },
-                ResizeExact(src, dst, w, h, ft) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].resize_exact(clamp(w), clamp(h), filtertype(ft));
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push resize_exact dst", !repeated)?;
-                },
-                ResizeFill(src, dst, w, h, ft) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].resize_to_fill(clamp(w), clamp(h), filtertype(ft));
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push resize_fill dst", !repeated)?;
-                },
-                Thumbnail(src, dst, w, h) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].thumbnail(clamp(w), clamp(h));
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push thumbnail dst", !repeated)?;
-                },
-                ThumbnailExact(src, dst, w, h) => {
-                    let s = img_index(src, &images);
-                    let img = images[s].thumbnail_exact(clamp(w), clamp(h));
-                    let d = put_img(dst, img, &mut images, &mut redraw, show);
-                    send_int(&mut to_rhai, d, "Editimg: cannot push thumbnail_exact dst", !repeated)?;
+                HistoryLen(img) => {
+                    set_last = false;
+                    Some(img_index("history_len", img, &images)
+                        .map(|i| Value::Int(images.history_len(i) as i64)))
+                },
+                PathOf(img, part) => {
+                    set_last = false;
+                    // "" for buffers that were not loaded from a file
+                    Some(img_index("path_of", img, &images).map(|i| Value::Str(
+                        images.origin(i).map(|o| o.part(*part)).unwrap_or_default()
+                    )))
                 },
                 Show(img) => {
-                    let i = img_index(img, &images);
-                    let old = show;
-                    show = i;
-                    if old != show { redraw = true; }
-                    send_int(&mut to_rhai, show as i64, "Editimg: cannot push show dst", !repeated)?;
+                    Some(img_index("show", img, &images).map(|i| {
+                        if i != show { redraw = true; }
+                        show = i;
+                        Value::Int(show as i64)
+                    }))
                 },
                 ShowNext => {
                     let old = show;
                     show = (show + 1) % images.len();
                     if old != show { redraw = true; }
-                    send_int(&mut to_rhai, show as i64, "Editimg: cannot push show_next dst",
-                        !repeated)?;
+                    Some(Ok(Value::Int(show as i64)))
                 },
                 ShowPrev => {
                     let old = show;
                     show = if show == 0 { images.len() - 1 } else { show - 1 };
                     if old != show { redraw = true; }
-                    send_int(&mut to_rhai, show as i64, "Editimg: cannot push show_prev dst",
-                        !repeated)?;
+                    Some(Ok(Value::Int(show as i64)))
                 },
                 Shown => {
-                    send_int(&mut to_rhai, show as i64, "Editimg: cannot push shown", !repeated)?;
                     set_last = false;
+                    Some(Ok(Value::Int(show as i64)))
                 },
-                Create(w, h) => {
-                    let img = RgbImage::new(clamp(w), clamp(h));
-                    images.push(DynamicImage::ImageRgb8(img));
-                    let d = (images.len() - 1) as i64;
-                    send_int(&mut to_rhai, d, "Editimg: cannot push create", !repeated)?;
-                },
-                Copy(src, dst, x, y) => {
-                    let src = img_index(src, &images);
-                    let dst = img_index(dst, &images);
-                    let mut d = std::mem::take(&mut images[dst]);
-                    let res = d.copy_from(&images[src], clamp(x), clamp(y));
-                    images[dst] = d;
-                    if dst == show { redraw = true; }
-                    let ok = if res.is_ok() { 1 } else { 0 };
-                    send_int(&mut to_rhai, ok, "Editimg: cannot push copy feedback", !repeated)?;
+                Zoom(f) => {
+                    set_last = false;
+                    if let Some((window, _)) = &mut gui {
+                        window.zoom(*f as f32);
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    Some(Ok(Value::Unit))
+                },
+                Pan(dx, dy) => {
+                    set_last = false;
+                    if let Some((window, _)) = &mut gui {
+                        window.pan(*dx as f32, *dy as f32);
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    Some(Ok(Value::Unit))
+                },
+                ZoomToFit => {
+                    set_last = false;
+                    if let Some((window, _)) = &mut gui {
+                        window.zoom_to_fit();
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    Some(Ok(Value::Unit))
+                },
+                ZoomToRect(r) => {
+                    set_last = false;
+                    if let Some((window, _)) = &mut gui {
+                        window.zoom_to_rect(r.clone());
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    Some(Ok(Value::Unit))
+                },
+                SetRenderFilter(f) => {
+                    set_last = false;
+                    Some(renderfilter("render_filter", f).map(|f| {
+                        if let Some((window, _)) = &mut gui {
+                            window.set_filter(f);
+                            redraw = true;
+                        }
+                        Value::Unit
+                    }))
+                },
+                SetPixelGrid(on) => {
+                    if let Some((window, _)) = &mut gui {
+                        window.set_grid(*on);
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    set_last = false;
+                    Some(Ok(Value::Unit))
+                },
+                SetStatus(mode) => {
+                    if let Some((window, _)) = &mut gui {
+                        window.set_mode(mode);
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    set_last = false;
+                    Some(Ok(Value::Unit))
+                },
+                OverlayText(lines) => {
+                    if let Some((window, _)) = &mut gui {
+                        window.set_overlay(lines.clone());
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    set_last = false;
+                    Some(Ok(Value::Unit))
+                },
+                ClearOverlay => {
+                    if let Some((window, _)) = &mut gui {
+                        window.clear_overlay();
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    set_last = false;
+                    Some(Ok(Value::Unit))
+                },
+                Bind(mode, key, description) => {
+                    set_last = false;
+                    Some(Ok(Value::Int(keymap.bind(mode, key, description))))
+                },
+                EnterMode(mode) => {
+                    keymap.enter(mode);
+                    if let Some((window, _)) = &mut gui {
+                        window.set_mode(keymap.mode());
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    set_last = false;
+                    Some(Ok(Value::Unit))
+                },
+                NextBinding => {
+                    set_last = false;
+                    paired_text = false;
+                    if let Some((window, _)) = &mut gui {
+                        let mut res = None;
+                        let mut changed = false;
+                        while let Some(i) = inputs.pop_front() {
+                            // ? is asked for as text so it works with any keyboard layout
+                            if i.key == "text" && i.text == "?" {
+                                keymap_help = !keymap_help;
+                                window.set_overlay(if keymap_help {
+                                    keymap.help()
+                                } else {
+                                    Vec::new()
+                                });
+                                changed = true;
+                            }
+                            if i.key == "text" && i.text == ":" {
+                                if keymap_help {
+                                    keymap_help = false;
+                                    window.clear_overlay();
+                                    changed = true;
+                                }
+                                res = Some(Ok(Value::Str("palette".to_string())));
+                                break;
+                            }
+                            if i.key == "text" || i.is_click || i.is_drag || i.is_release
+                                || keymap::is_modifier(&i.key) {
+                                continue;
+                            }
+                            if let Some(id) = keymap.lookup(&i) {
+                                // the text of the key would end up in a prompt of the binding
+                                if inputs.front().is_some_and(|n| n.key == "text") {
+                                    inputs.pop_front();
+                                }
+                                if keymap_help {
+                                    keymap_help = false;
+                                    window.clear_overlay();
+                                    changed = true;
+                                }
+                                res = Some(Ok(Value::Int(id)));
+                                break;
+                            }
+                        }
+                        if changed {
+                            window.redraw_all()?;
+                            drawn = true;
+                        }
+                        res
+                    } else {
+                        // no keys will ever come
+                        Some(Ok(Value::Unit))
+                    }
+                },
+                Palette => {
+                    set_last = false;
+                    if let Some((window, _)) = &mut gui {
+                        let mut changed = palette.is_none();
+                        if changed && paired_text { inputs.pop_front(); }
+                        paired_text = false;
+                        let p = palette.get_or_insert_with(|| {
+                            palette::Palette::new(keymap.active(), show)
+                        });
+                        let mut res = None;
+                        while let Some(i) = inputs.pop_front() {
+                            changed = true;
+                            res = p.input(&i);
+                            if res.is_some() { break; }
+                        }
+                        if changed {
+                            window.set_prompt(if res.is_some() { Vec::new() } else { p.lines() });
+                            window.redraw_all()?;
+                            drawn = true;
+                        }
+                        if res.is_some() { palette = None; }
+                        res.map(Ok)
+                    } else {
+                        // nothing to choose with
+                        Some(Ok(Value::Unit))
+                    }
+                },
+                Message(text) => {
+                    if let Some(log) = &mut log {
+                        log.push_str(text);
+                    }
+                    if let Some((window, _)) = &mut gui {
+                        window.push_message(text);
+                        window.redraw_all()?;
+                        drawn = true;
+                    }
+                    set_last = false;
+                    Some(Ok(Value::Unit))
+                },
+                SelectDrag => {
+                    set_last = false;
+                    paired_text = false;
+                    let mut res = None;
+                    let mut changed = false;
+                    while let Some(i) = inputs.pop_front() {
+                        changed = true;
+                        if i.is_click && i.key == "left" {
+                            drag_sel = Some(RectXY::new(i.x, i.y, i.x, i.y));
+                        } else if i.is_click && i.key == "right" {
+                            // cancel
+                            drag_sel = None;
+                            res = Some(Ok(Value::Unit));
+                            break;
+                        } else if let Some(r) = &mut drag_sel {
+                            if i.is_drag && i.left_down {
+                                r.qx = i.x;
+                                r.qy = i.y;
+                            } else if i.is_release && i.button == "left" {
+                                let img = &images[show];
+                                res = Some(Ok(drag_rect(r, img.width(), img.height())));
+                                drag_sel = None;
+                                break;
+                            }
+                        }
+                    }
+                    if let Some((window, _)) = &mut gui {
+                        // only redraw the selection when an input could have moved it
+                        if changed {
+                            window.clear_rects();
+                            window.redraw_texture()?;
+                            drawn = true;
+                            if let Some(r) = &drag_sel {
+                                rects_xy.push(RectXY::new(
+                                    r.px.min(r.qx), r.py.min(r.qy),
+                                    r.px.max(r.qx), r.py.max(r.qy),
+                                ));
+                            }
+                        }
+                        res
+                    } else {
+                        Some(Ok(Value::Unit))
+                    }
+                },
+                Prompt(msg, kind, default) => {
+                    set_last = false;
+                    if let Some((window, _)) = &mut gui {
+                        let mut changed = prompt.is_none();
+                        // the text of the key the script just read is not an answer
+                        if changed && paired_text { inputs.pop_front(); }
+                        paired_text = false;
+                        let p = prompt.get_or_insert_with(|| {
+                            prompt::Prompt::new(msg, kind.clone(), default.clone())
+                        });
+                        let mut res = None;
+                        while let Some(i) = inputs.pop_front() {
+                            changed = true;
+                            res = p.input(&i);
+                            if res.is_some() { break; }
+                        }
+                        if changed {
+                            window.set_prompt(if res.is_some() { Vec::new() } else { p.lines() });
+                            window.redraw_all()?;
+                            drawn = true;
+                        }
+                        if res.is_some() { prompt = None; }
+                        res.map(Ok)
+                    } else {
+                        // nobody to answer
+                        Some(Ok(Value::Unit))
+                    }
                 },
                 Repeat => {
                     repeat = true;
+                    Some(Ok(Value::Unit))
                 },
-            }
-            if pop {
+            };
+            if let Some(result) = reply {
+                let (id, wait) = (*id, *wait);
                 polls.pop_front();
+                match result {
+                    // a repeated call has no script waiting for the answer
+                    Err(e) if repeated || !wait => println!("{e}"),
+                    _ if repeated || !wait => {},
+                    result => {
+                        to_rhai.send(Reply { id, result })
+                            .map_err(|_| "Editimg: cannot push reply")?;
+                    },
+                }
             }
         }
 
@@ -393,10 +849,19 @@ pub fn main() -> Result<(), String> {
 
         if die {
             // do not yield error on purpose: channel maybe closed, and that is alright.
-            let _ = to_rhai.send(RhaiMsg::Killed).map_err(|_| "Editimg: cannot push die signal");
+            for Request { id, .. } in polls.drain(..).filter(|r| r.wait) {
+                let _ = to_rhai.send(Reply { id, result: Err("Editimg: killed".to_string()) });
+            }
             break;
         }
 
+        let Some((window, _)) = &mut gui else {
+            rects_uv.clear();
+            rects_xy.clear();
+            continue;
+        };
+        window.set_buffer(show, images.len());
+
         if rects_uv.len() + rects_xy.len() > 0 {
             drawn = true;
             while let Some(r) = rects_uv.pop() { window.draw_rect_uv(r)?; }
@@ -413,71 +878,390 @@ pub fn main() -> Result<(), String> {
         }
     }
 
-    println!("Editimg: finished.");
-    Ok(())
+    Ok(killed)
 }
 
-fn send_int(to_rhai: &mut spmc::Sender<scripting::RhaiMsg>, int: i64, err: &str, send: bool)
-    -> Result<(), String>
-{
-    if !send { return Ok(()); }
-    to_rhai.send(RhaiMsg::Int(int))
-        .map_err(|_| format!("Editimg: cannot push {}", err))
+fn image_op(
+    msg: &HostMsg, images: &mut Buffers, redraw: &mut bool, show: usize, defaults: &Defaults
+) -> Result<Value, String> {
+    use HostMsg::*;
+    match msg {
+        Crop(src, dst, px, py, qx, qy) => {
+            let s = img_index("crop", src, images)?;
+            if px == qx || py == qy {
+                return Err("crop: select an area of at least 1x1 pixels".to_string());
+            }
+            let (w, h) = (images[s].width(), images[s].height());
+            let (px, py, qx, qy) = img_crop(*px, *py, *qx, *qy, w, h)?;
+            let img = images[s].crop_imm(px, py, qx - px, qy - py);
+            put_img("crop", Some(s), dst, img, images, redraw, show)
+        },
+        Save(source, template) => {
+            save_buffer("save", source, template, &SaveOptions::default(), images, defaults)
+        },
+        SaveWith(source, template, options) => {
+            save_buffer("save_with", source, template, options, images, defaults)
+        },
+        SaveMaxBytes(source, template, max, format, fit) => {
+            let op = "save_max_bytes";
+            let s = img_index(op, source, images)?;
+            let max = usize::try_from(*max).ok().filter(|m| *m > 0)
+                .ok_or_else(|| format!("{op}: bytes must be more than 0, got {max}"))?;
+            let filter = filtertype(op, &fit.filter, defaults)?;
+            let origin = images.origin(s);
+            let path = expand_template(template, origin).map_err(|e| format!("{op}: {e}"))?;
+            let fitted = save::write_max_bytes(
+                &images[s], Path::new(&path), *format, max, fit, filter, origin
+            ).map_err(|e| format!("{op}: could not save buffer {s} as {path}: {e}"))?;
+            Ok(fitted.to_value())
+        },
+        Load(dst, path) => {
+            let (img, origin) = load_image(Path::new(path)).map_err(|e| format!("load: {e}"))?;
+            let res = put_img("load", None, dst, img, images, redraw, show)?;
+            if let Value::Int(d) = res {
+                images.set_origin(d as usize, Some(origin));
+            }
+            Ok(res)
+        },
+        FlipH(src, dst) => img_action("fliph", src, dst, images, redraw, show, DynamicImage::fliph),
+        FlipV(src, dst) => img_action("flipv", src, dst, images, redraw, show, DynamicImage::flipv),
+        Rot90(src, dst) => {
+            img_action("rotate90", src, dst, images, redraw, show, DynamicImage::rotate90)
+        },
+        Rot180(src, dst) => {
+            img_action("rotate180", src, dst, images, redraw, show, DynamicImage::rotate180)
+        },
+        Rot270(src, dst) => {
+            img_action("rotate270", src, dst, images, redraw, show, DynamicImage::rotate270)
+        },
+        Invert(src, dst) => {
+            let s = img_index("invert", src, images)?;
+            let mut r = images[s].clone();
+            r.invert();
+            put_img("invert", Some(s), dst, r, images, redraw, show)
+        },
+        Grayscale(src, dst) => {
+            img_action("grayscale", src, dst, images, redraw, show, DynamicImage::grayscale)
+        },
+        Blur(src, dst, sigma) => {
+            let s = img_index("blur", src, images)?;
+            let img = images[s].blur(*sigma as f32);
+            put_img("blur", Some(s), dst, img, images, redraw, show)
+        },
+        Unsharpen(src, dst, sigma, threshold) => {
+            let s = img_index("unsharpen", src, images)?;
+            let img = images[s].unsharpen(*sigma as f32, *threshold as i32);
+            put_img("unsharpen", Some(s), dst, img, images, redraw, show)
+        },
+        Filter3x3(src, dst, fltr) => {
+            let s = img_index("filter3x3", src, images)?;
+            let f = fltr.iter().map(|v| *v as f32).collect::<Vec<_>>();
+            let img = images[s].filter3x3(&f);
+            put_img("filter3x3", Some(s), dst, img, images, redraw, show)
+        },
+        AdjustContrast(src, dst, c) => {
+            let s = img_index("adjust_contrast", src, images)?;
+            let img = images[s].adjust_contrast(*c as f32);
+            put_img("adjust_contrast", Some(s), dst, img, images, redraw, show)
+        },
+        Brighten(src, dst, v) => {
+            let s = img_index("brighten", src, images)?;
+            let img = images[s].brighten(*v as i32);
+            put_img("brighten", Some(s), dst, img, images, redraw, show)
+        },
+        Huerotate(src, dst, v) => {
+            let s = img_index("huerotate", src, images)?;
+            let img = images[s].huerotate(*v as i32);
+            put_img("huerotate", Some(s), dst, img, images, redraw, show)
+        },
+        Resize(src, dst, w, h, ft) => {
+            let s = img_index("resize", src, images)?;
+            let (w, h) = (size("resize", "w", w)?, size("resize", "h", h)?);
+            let img = images[s].resize(w, h, filtertype("resize", ft, defaults)?);
+            put_img("resize", Some(s), dst, img, images, redraw, show)
+        },
+        ResizeExact(src, dst, w, h, ft) => {
+            let s = img_index("resize_exact", src, images)?;
+            let (w, h) = (size("resize_exact", "w", w)?, size("resize_exact", "h", h)?);
+            let img = images[s].resize_exact(w, h, filtertype("resize_exact", ft, defaults)?);
+            put_img("resize_exact", Some(s), dst, img, images, redraw, show)
+        },
+        ResizeFill(src, dst, w, h, ft) => {
+            let s = img_index("resize_fill", src, images)?;
+            let (w, h) = (size("resize_fill", "w", w)?, size("resize_fill", "h", h)?);
+            let img = images[s].resize_to_fill(w, h, filtertype("resize_fill", ft, defaults)?);
+            put_img("resize_fill", Some(s), dst, img, images, redraw, show)
+        },
+        Thumbnail(src, dst, w, h) => {
+            let s = img_index("thumbnail", src, images)?;
+            let (w, h) = (size("thumbnail", "w", w)?, size("thumbnail", "h", h)?);
+            let img = images[s].thumbnail(w, h);
+            put_img("thumbnail", Some(s), dst, img, images, redraw, show)
+        },
+        ThumbnailExact(src, dst, w, h) => {
+            let s = img_index("thumbnail_exact", src, images)?;
+            let (w, h) = (size("thumbnail_exact", "w", w)?, size("thumbnail_exact", "h", h)?);
+            let img = images[s].thumbnail_exact(w, h);
+            put_img("thumbnail_exact", Some(s), dst, img, images, redraw, show)
+        },
+        Create(w, h) => {
+            let img = RgbImage::new(size("create", "w", w)?, size("create", "h", h)?);
+            Ok(Value::Int(images.push(DynamicImage::ImageRgb8(img)) as i64))
+        },
+        Copy(src, dst, x, y) => {
+            let src = img_index("copy", src, images)?;
+            let dst = img_index("copy", dst, images)?;
+            let (x, y) = (coord("copy", "x", x)?, coord("copy", "y", y)?);
+            let mut d = images[dst].clone();
+            let res = d.copy_from(&images[src], x, y);
+            if res.is_ok() {
+                images.replace(dst, d);
+                if dst == show { *redraw = true; }
+            }
+            Ok(Value::Bool(res.is_ok()))
+        },
+        Undo(img) => {
+            let i = img_index("undo", img, images)?;
+            let ok = images.undo(i);
+            if ok && i == show { *redraw = true; }
+            Ok(Value::Bool(ok))
+        },
+        Redo(img) => {
+            let i = img_index("redo", img, images)?;
+            let ok = images.redo(i);
+            if ok && i == show { *redraw = true; }
+            Ok(Value::Bool(ok))
+        },
+        _ => Err(format!("Editimg: {msg:?} is not an image operation")),
+    }
 }
 
-#[allow(clippy::too_many_arguments)]
 fn img_action(
-    src: &i64, dst: &i64, images: &mut Vec<DynamicImage>, redraw: &mut bool, show: usize,
-    to_rhai: &mut spmc::Sender<scripting::RhaiMsg>, err: &str, f: fn(&DynamicImage) -> DynamicImage,
-    send: bool,
-) -> Result<(), String> {
-    let s = img_index(src, images);
+    op: &str, src: &i64, dst: &i64, images: &mut Buffers, redraw: &mut bool, show: usize,
+    f: fn(&DynamicImage) -> DynamicImage,
+) -> Result<Value, String> {
+    let s = img_index(op, src, images)?;
     let img = f(&images[s]);
-    let d = put_img(dst, img, images, redraw, show);
-    send_int(to_rhai, d, err, send)
+    put_img(op, Some(s), dst, img, images, redraw, show)
+}
+
+fn save_buffer(
+    op: &str, source: &i64, template: &str, options: &SaveOptions, images: &Buffers,
+    defaults: &Defaults,
+) -> Result<Value, String> {
+    let s = img_index(op, source, images)?;
+    let origin = images.origin(s);
+    let path = expand_template(template, origin).map_err(|e| format!("{op}: {e}"))?;
+    save::write_image(&images[s], Path::new(&path), options, defaults.jpeg_quality, origin)
+        .map_err(|e| format!("{op}: could not save buffer {s} as {path}: {e}"))?;
+    Ok(Value::Unit)
+}
+
+/// Where commands, the library and modules are searched, in order: --script-dir, ./.editimg,
+/// the dirs of the config, the config dir and editimg in $XDG_DATA_DIRS.
+fn script_dirs(args: &[PathBuf], configured: &[PathBuf], cdir: &Path, xdg_data_dirs: &str)
+    -> Vec<PathBuf>
+{
+    let mut dirs = args.to_vec();
+    dirs.push(PathBuf::from(".editimg"));
+    dirs.extend(configured.iter().cloned());
+    dirs.push(cdir.to_path_buf());
+    let xdg_data_dirs = if xdg_data_dirs.is_empty() {
+        "/usr/local/share:/usr/share"
+    } else {
+        xdg_data_dirs
+    };
+    dirs.extend(xdg_data_dirs.split(':').filter(|d| !d.is_empty()).map(|d| {
+        Path::new(d).join("editimg")
+    }));
+    dirs
+}
+
+/// `import "name"` loads the first `name.rhai.rs` in the directories.
+fn module_resolver(dirs: &[PathBuf]) -> ModuleResolversCollection {
+    let mut resolvers = ModuleResolversCollection::new();
+    for dir in dirs {
+        resolvers.push(FileModuleResolver::new_with_path_and_extension(dir, "rhai.rs"));
+    }
+    resolvers
+}
+
+/// The code of the first `<name>.rhai.rs` in the directories or else of the bundled script,
+/// with where it came from.
+fn load_script(dirs: &[PathBuf], name: &str) -> Result<(String, String), String> {
+    match find_script(dirs, name) {
+        Ok(path) => std::fs::read_to_string(&path)
+            .map(|code| (path.display().to_string(), code))
+            .map_err(|e| format!("could not read {}: {e}", path.display())),
+        Err(e) => embedded::script(name)
+            .map(|code| ("built in".to_string(), code.to_string()))
+            .ok_or(e),
+    }
+}
+
+fn init(cdir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(cdir)
+        .map_err(|e| format!("Editimg: could not create {}: {e}", cdir.display()))?;
+    for (name, code) in embedded::SCRIPTS {
+        let path = cdir.join(format!("{name}.rhai.rs"));
+        // never overwrite scripts that may have been edited
+        if path.exists() {
+            println!("kept {}", path.display());
+            continue;
+        }
+        std::fs::write(&path, code)
+            .map_err(|e| format!("Editimg: could not write {}: {e}", path.display()))?;
+        println!("installed {}", path.display());
+    }
+    Ok(())
+}
+
+fn list(dirs: &[PathBuf]) -> Result<(), String> {
+    let mut names = BTreeSet::new();
+    for entries in dirs.iter().filter_map(|d| std::fs::read_dir(d).ok()) {
+        for entry in entries.flatten() {
+            let file = entry.file_name();
+            if let Some(name) = file.to_str().and_then(|n| n.strip_suffix(".rhai.rs")) {
+                names.insert(name.to_string());
+            }
+        }
+    }
+    names.extend(embedded::SCRIPTS.iter().map(|(name, _)| name.to_string()));
+    names.remove("lib");
+    let w = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+    for name in names {
+        let source = find_script(dirs, &name)
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|_| "built in".to_string());
+        println!("{name:<w$}  {source}");
+    }
+    Ok(())
 }
 
-fn filtertype(f: &str) -> FilterType {
+/// The first `<name>.rhai.rs` in the directories.
+fn find_script(dirs: &[PathBuf], name: &str) -> Result<PathBuf, String> {
+    let file = format!("{name}.rhai.rs");
+    dirs.iter()
+        .map(|d| d.join(&file))
+        .find(|p| p.is_file())
+        .ok_or_else(|| format!("{file} not found in {dirs:?}"))
+}
+
+fn parse_set(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got \"{s}\""))
+}
+
+fn apply_args(mut config: Config, args: &Args) -> Config {
+    let w = &mut config.window;
+    if let Some(v) = args.width { w.width = v; }
+    if let Some(v) = args.height { w.height = v; }
+    if let Some(v) = &args.title { w.title = v.clone(); }
+    if let Some(v) = &args.class { w.class = v.clone(); }
+    let d = &mut config.defaults;
+    if let Some(v) = &args.resize_filter { d.resize_filter = v.clone(); }
+    if let Some(v) = args.jpeg_quality { d.jpeg_quality = v; }
+    if let Some(v) = args.undo_limit { d.undo_limit = v; }
+    config
+}
+
+fn load_image(path: &Path) -> Result<(DynamicImage, Origin), String> {
+    let reader = IR::open(path)
+        .and_then(IR::with_guessed_format)
+        .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+    let format = reader.format();
+    let decode_error = |e| format!("could not decode {}: {}", path.display(), e);
+    let mut decoder = reader.into_decoder().map_err(decode_error)?;
+    // broken metadata is no reason to refuse the image
+    let metadata = Metadata {
+        icc: decoder.icc_profile().ok().flatten(),
+        exif: decoder.exif_metadata().ok().flatten(),
+    };
+    let img = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+    Ok((img, Origin { path: path.to_path_buf(), format, metadata }))
+}
+
+fn filtertype(op: &str, f: &str, defaults: &Defaults) -> Result<FilterType, String> {
+    let f = if f.is_empty() || f.eq_ignore_ascii_case("default") {
+        defaults.resize_filter.as_str()
+    } else {
+        f
+    };
+    resize_filter(f).ok_or_else(|| format!("{op}: unknown filter \"{f}\""))
+}
+
+fn renderfilter(op: &str, f: &str) -> Result<RenderFilter, String> {
     match f.to_lowercase().as_ref() {
-        "nearest" => FilterType::Nearest,
-        "triangle" => FilterType::Triangle,
-        "catmullrom" => FilterType::CatmullRom,
-        "catmull-rom" => FilterType::CatmullRom,
-        "gaussian" => FilterType::Gaussian,
-        "lanczos3" => FilterType::Lanczos3,
-        "lanczos" => FilterType::Lanczos3,
-        _ => FilterType::Triangle,
+        "nearest" => Ok(RenderFilter::Nearest),
+        "linear" => Ok(RenderFilter::Linear),
+        _ => Err(format!("{op}: unknown filter \"{f}\"")),
     }
 }
 
-fn clamp(v: &i64) -> u32 {
-    (*v).max(0).min(u32::MAX as i64) as u32
+fn size(op: &str, name: &str, v: &i64) -> Result<u32, String> {
+    match u32::try_from(*v) {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(format!("{op}: {name} must be between 1 and {}, got {v}", u32::MAX)),
+    }
 }
 
+fn coord(op: &str, name: &str, v: &i64) -> Result<u32, String> {
+    u32::try_from(*v)
+        .map_err(|_| format!("{op}: {name} must be between 0 and {}, got {v}", u32::MAX))
+}
+
+/// A new buffer takes the origin of the buffer it was made from.
 fn put_img(
-    dst: &i64, img: DynamicImage, images: &mut Vec<DynamicImage>, redraw: &mut bool, show: usize
-) -> i64 {
+    op: &str, src: Option<usize>, dst: &i64, img: DynamicImage, images: &mut Buffers,
+    redraw: &mut bool, show: usize,
+) -> Result<Value, String> {
     if *dst < 0 || *dst == images.len() as i64 {
-        images.push(img);
-        images.len() as i64 - 1
+        let origin = src.and_then(|s| images.origin(s).cloned());
+        let d = images.push(img);
+        images.set_origin(d, origin);
+        Ok(Value::Int(d as i64))
     } else {
-        let d = img_index(dst, images);
-        images[d] = img;
+        let d = img_index(op, dst, images)?;
+        images.replace(d, img);
         if d == show { *redraw = true; }
-        d as i64
+        Ok(Value::Int(d as i64))
     }
 }
 
-fn img_index(i: &i64, images: &[DynamicImage]) -> usize {
-    ((*i).max(0) as usize).min(images.len() - 1)
+fn img_index(op: &str, i: &i64, images: &Buffers) -> Result<usize, String> {
+    match usize::try_from(*i) {
+        Ok(i) if i < images.len() => Ok(i),
+        _ => Err(format!("{op}: buffer {i} does not exist, there are {} buffers", images.len())),
+    }
 }
 
-fn img_crop(px: i64, py: i64, qx: i64, qy: i64) -> (u32, u32, u32, u32) {
-    let npx = px.min(qx).max(0) as u32;
-    let npy = py.min(qy).max(0) as u32;
-    let nqx = px.max(qx).max(0) as u32;
-    let nqy = py.max(qy).max(0) as u32;
-    (npx, npy, nqx, nqy)
+/// Final selection of a drag, ordered and clamped to the image.
+fn drag_rect(r: &RectXY, w: u32, h: u32) -> Value {
+    let (w, h) = (w as i64, h as i64);
+    let px = (r.px.min(r.qx) as i64).clamp(0, w);
+    let py = (r.py.min(r.qy) as i64).clamp(0, h);
+    let qx = (r.px.max(r.qx) as i64).clamp(0, w);
+    let qy = (r.py.max(r.qy) as i64).clamp(0, h);
+    Value::Array(vec![Value::Int(px), Value::Int(py), Value::Int(qx), Value::Int(qy)])
+}
+
+/// Orders the corners of a crop and checks that they lie within a w x h image.
+fn img_crop(
+    px: i64, py: i64, qx: i64, qy: i64, w: u32, h: u32
+) -> Result<(u32, u32, u32, u32), String> {
+    let inside = |name: &str, v: i64, max: u32| match u32::try_from(v) {
+        Ok(v) if v <= max => Ok(v),
+        _ => Err(format!("crop: {name} must be between 0 and {max}, got {v}")),
+    };
+    let (npx, nqx) = if px <= qx { (("px", px), ("qx", qx)) } else { (("qx", qx), ("px", px)) };
+    let (npy, nqy) = if py <= qy { (("py", py), ("qy", qy)) } else { (("qy", qy), ("py", py)) };
+    Ok((
+        inside(npx.0, npx.1, w)?,
+        inside(npy.0, npy.1, h)?,
+        inside(nqx.0, nqx.1, w)?,
+        inside(nqy.0, nqy.1, h)?,
+    ))
 }
 
 trait Vital<T> {
@@ -496,3 +1280,55 @@ impl<T, U: std::fmt::Display> Vital<T> for Result<T, U> {
     }
 }
 
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+
+    #[test]
+    fn test_img_crop(){
+        assert_eq!(img_crop(1, 2, 3, 4, 10, 10), Ok((1, 2, 3, 4)));
+        assert_eq!(img_crop(3, 4, 1, 2, 10, 10), Ok((1, 2, 3, 4)));
+        assert_eq!(img_crop(0, 0, 10, 8, 10, 8), Ok((0, 0, 10, 8)));
+        assert_eq!(
+            img_crop(-1, 0, 5, 5, 10, 10),
+            Err("crop: px must be between 0 and 10, got -1".to_string())
+        );
+        assert_eq!(
+            img_crop(5, 0, 11, 5, 10, 10),
+            Err("crop: qx must be between 0 and 10, got 11".to_string())
+        );
+        // the names follow the corners after ordering
+        assert_eq!(
+            img_crop(0, 12, 5, 3, 10, 10),
+            Err("crop: py must be between 0 and 10, got 12".to_string())
+        );
+    }
+
+    #[test]
+    fn test_img_index(){
+        let mut images = Buffers::new(1024);
+        images.push(DynamicImage::new_rgb8(2, 2));
+        images.push(DynamicImage::new_rgb8(2, 2));
+        assert_eq!(img_index("show", &1, &images), Ok(1));
+        assert_eq!(
+            img_index("show", &2, &images),
+            Err("show: buffer 2 does not exist, there are 2 buffers".to_string())
+        );
+        assert!(img_index("show", &-1, &images).is_err());
+    }
+
+    #[test]
+    fn test_size_coord(){
+        assert_eq!(size("resize", "w", &1), Ok(1));
+        assert_eq!(size("resize", "w", &0), Err(format!(
+            "resize: w must be between 1 and {}, got 0", u32::MAX
+        )));
+        assert!(size("resize", "w", &(u32::MAX as i64 + 1)).is_err());
+        assert_eq!(coord("copy", "x", &0), Ok(0));
+        assert_eq!(coord("copy", "x", &-1), Err(format!(
+            "copy: x must be between 0 and {}, got -1", u32::MAX
+        )));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{
+    prompt::{ Prompt, PromptKind },
+    scripting::{ Arg, Command, COMMANDS, Input, Value },
+};
+
+/// Amount of matches listed at once.
+const SHOWN_MATCHES: usize = 8;
+
+struct Entry {
+    label: String,
+    command: Option<&'static Command>,
+    binding: i64,
+}
+
+/// Fuzzy search over the commands and key bindings, then ask for the arguments of a command.
+/// Answers with the id of a binding, [name, args...] for a command or () when cancelled.
+pub struct Palette {
+    entries: Vec<Entry>,
+    query: String,
+    selected: usize,
+    shown: i64,
+    chosen: Option<(&'static Command, Vec<Value>, Prompt)>,
+}
+
+impl Palette {
+    /// Bindings are (key, description, id).
+    pub fn new(bindings: Vec<(String, String, i64)>, shown: usize) -> Self {
+        let mut entries = bindings.into_iter()
+            .map(|(key, description, id)| Entry {
+                label: format!("{description} ({key})"), command: None, binding: id,
+            })
+            .collect::<Vec<_>>();
+        entries.extend(COMMANDS.iter().map(|c| Entry {
+            label: format!("{}  {}", c.name, c.description), command: Some(c), binding: -1,
+        }));
+        Self {
+            entries,
+            query: String::new(),
+            selected: 0,
+            shown: shown as i64,
+            chosen: None,
+        }
+    }
+
+    /// The query and the best matches, or the question for an argument.
+    pub fn lines(&self) -> Vec<String> {
+        if let Some((_, _, prompt)) = &self.chosen {
+            return prompt.lines();
+        }
+        let mut lines = vec![format!(": {}_", self.query)];
+        lines.extend(self.matches().iter().take(SHOWN_MATCHES).enumerate().map(|(i, e)| {
+            format!("{} {}", if i == self.selected { ">" } else { " " }, e.label)
+        }));
+        lines
+    }
+
+    pub fn input(&mut self, i: &Input) -> Option<Value> {
+        if let Some((command, mut args, mut prompt)) = self.chosen.take() {
+            match prompt.input(i) {
+                None => {},
+                Some(Value::Unit) => return Some(Value::Unit),
+                Some(v) => {
+                    args.push(v);
+                    return self.ask_args(command, args);
+                },
+            }
+            self.chosen = Some((command, args, prompt));
+            return None;
+        }
+        if i.is_click || i.is_drag || i.is_release {
+            return None;
+        }
+        let found = self.matches().len().min(SHOWN_MATCHES);
+        if i.key == "text" {
+            self.query.push_str(&i.text);
+            self.selected = 0;
+        } else if i.key == "backspace" {
+            self.query.pop();
+            self.selected = 0;
+        } else if i.key == "down" || i.key == "tab" {
+            self.selected = if found == 0 { 0 } else { (self.selected + 1) % found };
+        } else if i.key == "up" {
+            self.selected = if found == 0 { 0 } else { (self.selected + found - 1) % found };
+        } else if i.key == "return" || i.key == "kpenter" {
+            let (command, binding) = {
+                let e = self.matches().into_iter().nth(self.selected)?;
+                (e.command, e.binding)
+            };
+            let Some(command) = command else {
+                return Some(Value::Int(binding));
+            };
+            return self.ask_args(command, Vec::new());
+        } else if i.key == "escape" || i.key == "termination" {
+            return Some(Value::Unit);
+        }
+        None
+    }
+
+    /// Ask for the next argument, or answer with the call once there are no more.
+    fn ask_args(&mut self, command: &'static Command, mut args: Vec<Value>) -> Option<Value> {
+        let Some(arg) = command.args.get(args.len()) else {
+            let mut call = vec![Value::Str(command.name.to_string())];
+            call.append(&mut args);
+            return Some(Value::Array(call));
+        };
+        let shown = Some(Value::Int(self.shown));
+        let (name, kind, default) = match arg {
+            Arg::Src => ("src", PromptKind::Int, shown),
+            Arg::Dst => ("dst", PromptKind::Int, shown),
+            Arg::Int(n) => (*n, PromptKind::Int, None),
+            Arg::Float(n) => (*n, PromptKind::Float, None),
+            Arg::Bool(n) => (*n, PromptKind::Bool, None),
+            Arg::Text(n) => (*n, PromptKind::Text, None),
+            Arg::Filter => {
+                let options = choice(&[
+                    "default", "nearest", "triangle", "catmullrom", "gaussian", "lanczos3",
+                ]);
+                ("filter", options, Some(Value::Str("default".to_string())))
+            },
+            Arg::RenderFilter => ("filter", choice(&["nearest", "linear"]), None),
+        };
+        let prompt = Prompt::new(&format!("{} {name}: ", command.name), kind, default);
+        self.chosen = Some((command, args, prompt));
+        None
+    }
+
+    fn matches(&self) -> Vec<&Entry> {
+        let mut scored = self.entries.iter()
+            .filter_map(|e| fuzzy_score(&self.query, &e.label).map(|s| (s, e)))
+            .collect::<Vec<_>>();
+        // stable, so equal scores keep bindings before commands
+        scored.sort_by_key(|(s, _)| -s);
+        scored.into_iter().map(|(_, e)| e).collect()
+    }
+}
+
+fn choice(options: &[&str]) -> PromptKind {
+    PromptKind::Choice(options.iter().map(|o| o.to_string()).collect())
+}
+
+/// None when the query is not a subsequence of the text, higher is a better match.
+/// Characters that follow each other or start a word count extra.
+fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut prev: Option<usize> = None;
+    for q in query.to_lowercase().chars() {
+        let found = pos + text[pos..].iter().position(|c| *c == q)?;
+        score += 1;
+        if prev.is_some_and(|p| p + 1 == found) {
+            score += 4;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 2;
+        }
+        score -= (found - pos).min(3) as i64;
+        prev = Some(found);
+        pos = found + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use sdl2::keyboard::Mod;
+
+    fn key(k: &str) -> Input {
+        Input::key(k.to_string(), Mod::NOMOD)
+    }
+
+    fn text(t: &str) -> Input {
+        Input::text(t.to_string())
+    }
+
+    #[test]
+    fn test_fuzzy_score(){
+        assert!(fuzzy_score("", "invert").is_some());
+        assert!(fuzzy_score("xyz", "invert").is_none());
+        assert!(fuzzy_score("rot9", "rotate90").is_some());
+        assert!(fuzzy_score("inv", "invert") > fuzzy_score("inv", "pixel_grid invisible"));
+        assert!(fuzzy_score("re", "resize") > fuzzy_score("re", "rotate"));
+    }
+
+    #[test]
+    fn test_palette_binding(){
+        let mut p = Palette::new(vec![("C-z".to_string(), "undo".to_string(), 7)], 0);
+        assert!(p.input(&text("und")).is_none());
+        assert_eq!(p.lines()[1], "> undo (C-z)");
+        assert!(matches!(p.input(&key("return")), Some(Value::Int(7))));
+    }
+
+    #[test]
+    fn test_palette_command(){
+        let mut p = Palette::new(Vec::new(), 2);
+        assert!(p.input(&text("blur")).is_none());
+        assert!(p.input(&key("return")).is_none());
+        // src and dst default to the shown buffer
+        assert!(p.input(&key("return")).is_none());
+        assert!(p.input(&key("return")).is_none());
+        assert!(p.input(&text("1.5")).is_none());
+        let Some(Value::Array(call)) = p.input(&key("return")) else { panic!("no call") };
+        assert!(matches!(&call[..], [Value::Str(n), Value::Int(2), Value::Int(2), Value::Float(s)]
+            if n == "blur" && *s == 1.5));
+
+        let mut p = Palette::new(Vec::new(), 0);
+        assert!(p.input(&text("zoom_to_fit")).is_none());
+        assert!(matches!(p.input(&key("return")), Some(Value::Array(c)) if c.len() == 1));
+
+        let mut p = Palette::new(Vec::new(), 0);
+        assert!(p.input(&text("save")).is_none());
+        assert!(p.input(&key("return")).is_none());
+        assert!(matches!(p.input(&key("escape")), Some(Value::Unit)));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::scripting::{ Input, Value };
+
+use std::io::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptKind {
+    Text,
+    Int,
+    Float,
+    Bool,
+    Choice(Vec<String>),
+}
+
+/// A question asked on the host side, answered with window input.
+pub struct Prompt {
+    msg: String,
+    kind: PromptKind,
+    default: Option<Value>,
+    buf: String,
+}
+
+impl Prompt {
+    pub fn new(msg: &str, kind: PromptKind, default: Option<Value>) -> Self {
+        let prompt = Self {
+            msg: msg.to_string(),
+            kind,
+            default,
+            buf: String::new(),
+        };
+        prompt.echo(&prompt.question());
+        prompt
+    }
+
+    /// The message with the options and default filled in.
+    pub fn question(&self) -> String {
+        let mut q = String::new();
+        if let PromptKind::Choice(options) = &self.kind {
+            for (i, o) in options.iter().enumerate() {
+                q.push_str(&format!("{}) {}\n", i + 1, o));
+            }
+        }
+        q.push_str(&self.msg);
+        if self.kind == PromptKind::Bool {
+            q.push_str("(y/n) ");
+        }
+        if let Some(d) = &self.default {
+            q.push_str(&format!("[{}] ", show_value(d)));
+        }
+        q
+    }
+
+    /// The question and the answer typed so far, as lines to show in the window.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = self.question().split('\n').map(str::to_string).collect::<Vec<_>>();
+        let answer = format!("{}{}_", lines.pop().unwrap_or_default(), self.buf);
+        lines.push(answer);
+        lines
+    }
+
+    /// Feed an input event, returns the answer once the prompt is done.
+    /// Escape cancels the prompt, which answers with ().
+    pub fn input(&mut self, i: &Input) -> Option<Value> {
+        if i.is_click || i.is_drag || i.is_release {
+            return None;
+        }
+        if i.key == "text" {
+            if self.kind == PromptKind::Bool {
+                return match i.text.to_lowercase().as_ref() {
+                    "y" => self.done(Value::Bool(true)),
+                    "n" => self.done(Value::Bool(false)),
+                    _ => None,
+                };
+            }
+            let accepted = i.text.chars().filter(|c| self.accepts(*c)).collect::<String>();
+            self.buf.push_str(&accepted);
+            self.echo(&accepted);
+        } else if i.key == "backspace" {
+            if self.buf.pop().is_some() {
+                self.echo("\x08 \x08");
+            }
+        } else if i.key == "return" || i.key == "kpenter" {
+            match self.parse() {
+                Some(v) => return self.done(v),
+                None => {
+                    self.buf.clear();
+                    self.echo(" [invalid]\n");
+                    self.echo(&self.question());
+                },
+            }
+        } else if i.key == "escape" || i.key == "termination" {
+            self.echo(" [cancelled]\n");
+            return Some(Value::Unit);
+        }
+        None
+    }
+
+    fn accepts(&self, c: char) -> bool {
+        match self.kind {
+            PromptKind::Int => c.is_ascii_digit() || c == '-',
+            PromptKind::Float => c.is_ascii_digit() || "-+.eE".contains(c),
+            _ => true,
+        }
+    }
+
+    fn parse(&self) -> Option<Value> {
+        if self.buf.is_empty() && self.kind != PromptKind::Text {
+            return self.default.clone();
+        }
+        match &self.kind {
+            PromptKind::Text => Some(Value::Str(self.buf.clone())),
+            PromptKind::Int => self.buf.parse().ok().map(Value::Int),
+            PromptKind::Float => self.buf.parse().ok().map(Value::Float),
+            PromptKind::Bool => None,
+            PromptKind::Choice(options) => {
+                // either the number in front of the option or the option itself
+                let chosen = self.buf.parse::<usize>().ok()
+                    .and_then(|n| options.get(n.wrapping_sub(1)))
+                    .or_else(|| options.iter().find(|o| **o == self.buf));
+                chosen.map(|o| Value::Str(o.clone()))
+            },
+        }
+    }
+
+    fn done(&self, v: Value) -> Option<Value> {
+        self.echo(&format!(" [{}]\n", show_value(&v)));
+        Some(v)
+    }
+
+    fn echo(&self, s: &str) {
+        print!("{s}");
+        let _ = std::io::stdout().flush();
+    }
+}
+
+fn show_value(v: &Value) -> String {
+    match v {
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Bool(true) => "y".to_string(),
+        Value::Bool(false) => "n".to_string(),
+        Value::Str(s) => s.clone(),
+        v => format!("{v:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use sdl2::keyboard::Mod;
+
+    fn key(k: &str) -> Input {
+        Input::key(k.to_string(), Mod::NOMOD)
+    }
+
+    fn typed(p: &mut Prompt, text: &str) -> Option<Value> {
+        let mut res = p.input(&Input::text(text.to_string()));
+        if res.is_none() {
+            res = p.input(&key("return"));
+        }
+        res
+    }
+
+    #[test]
+    fn test_prompt_int(){
+        let mut p = Prompt::new("", PromptKind::Int, None);
+        assert!(matches!(typed(&mut p, "-12a"), Some(Value::Int(-12))));
+        let mut p = Prompt::new("", PromptKind::Int, Some(Value::Int(3)));
+        assert!(matches!(typed(&mut p, ""), Some(Value::Int(3))));
+        let mut p = Prompt::new("", PromptKind::Int, None);
+        assert!(typed(&mut p, "").is_none());
+        assert!(typed(&mut p, "1-").is_none());
+        assert!(matches!(p.input(&key("escape")), Some(Value::Unit)));
+    }
+
+    #[test]
+    fn test_prompt_float_bool(){
+        let mut p = Prompt::new("", PromptKind::Float, None);
+        assert!(matches!(typed(&mut p, "1.5"), Some(Value::Float(f)) if f == 1.5));
+        let mut p = Prompt::new("", PromptKind::Bool, None);
+        assert!(matches!(typed(&mut p, "Y"), Some(Value::Bool(true))));
+        let mut p = Prompt::new("", PromptKind::Bool, Some(Value::Bool(false)));
+        assert!(matches!(typed(&mut p, ""), Some(Value::Bool(false))));
+    }
+
+    #[test]
+    fn test_prompt_lines(){
+        let options = vec!["jpg".to_string(), "png".to_string()];
+        let mut p = Prompt::new("format: ", PromptKind::Choice(options), None);
+        assert!(p.input(&Input::text("p".to_string())).is_none());
+        assert_eq!(p.lines(), vec!["1) jpg", "2) png", "format: p_"]);
+    }
+
+    #[test]
+    fn test_prompt_choice(){
+        let options = vec!["jpg".to_string(), "png".to_string()];
+        let mut p = Prompt::new("", PromptKind::Choice(options.clone()), None);
+        assert!(matches!(typed(&mut p, "2"), Some(Value::Str(s)) if s == "png"));
+        let mut p = Prompt::new("", PromptKind::Choice(options.clone()), None);
+        assert!(matches!(typed(&mut p, "jpg"), Some(Value::Str(s)) if s == "jpg"));
+        let mut p = Prompt::new("", PromptKind::Choice(options), None);
+        assert!(typed(&mut p, "3").is_none());
+    }
+}
@@ -0,0 +1,55 @@
+use rhai::{ Engine, Scope, Dynamic, AST };
+
+use rustyline::{
+    DefaultEditor,
+    error::ReadlineError,
+};
+
+use std::path::Path;
+
+pub fn run(engine: &Engine, scope: &mut Scope, ast: &AST, history: &Path) {
+    let mut rl = match DefaultEditor::new() {
+        Ok(rl) => rl,
+        Err(e) => {
+            println!("Editimg: could not start repl: {e}");
+            return;
+        },
+    };
+    // no history yet is fine
+    let _ = rl.load_history(history);
+
+    // keep the functions of the command around, statements are only run once
+    let mut main_ast = ast.clone_functions_only();
+
+    println!("Rhai REPL, ctrl-d to quit.");
+    loop {
+        let line = match rl.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                println!("Editimg: repl read error: {e}");
+                break;
+            },
+        };
+        if line.trim().is_empty() { continue; }
+        let _ = rl.add_history_entry(line.as_str());
+
+        match engine.compile_with_scope(scope, &line) {
+            Ok(ast) => {
+                main_ast += ast;
+                match engine.eval_ast_with_scope::<Dynamic>(scope, &main_ast) {
+                    Ok(res) if !res.is_unit() => println!("{res}"),
+                    Ok(_) => {},
+                    Err(e) => println!("Rhai error: {e}"),
+                }
+                main_ast.clear_statements();
+            },
+            Err(e) => println!("Rhai compile error: {e}"),
+        }
+    }
+
+    if let Err(e) = rl.save_history(history) {
+        println!("Editimg: could not save repl history: {e}");
+    }
+}
@@ -0,0 +1,528 @@
+use crate::{
+    buffers::Origin,
+    scripting::Value,
+};
+
+use image::{
+    DynamicImage,
+    ImageEncoder,
+    ImageFormat,
+    imageops::FilterType as ResizeFilter,
+    codecs::{
+        avif::AvifEncoder,
+        jpeg::JpegEncoder,
+        png::{ CompressionType, FilterType, PngEncoder },
+        webp::WebPEncoder,
+    },
+};
+use rhai::Map;
+
+use std::{
+    io::Cursor,
+    path::Path,
+};
+
+/// Images are not made smaller than this by save_max_bytes.
+const MIN_SIDE: u32 = 16;
+
+/// How an image is written, whatever is left out comes from the path and the config.
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    pub format: Option<ImageFormat>,
+    /// 1 to 100, for jpeg, webp and avif.
+    pub quality: Option<u8>,
+    /// For png.
+    pub compression: Option<CompressionType>,
+    /// For jpeg, the encoder of image can not do this so jpeg-encoder writes these.
+    pub progressive: bool,
+    /// For webp, which is lossless unless there is a quality.
+    pub lossless: Option<bool>,
+    /// Keep the ICC profile and EXIF data of the file the buffer was loaded from.
+    pub metadata: bool,
+}
+
+impl SaveOptions {
+    /// From the map given to save_with, unknown keys are an error.
+    pub fn from_map(map: &Map) -> Result<Self, String> {
+        let mut options = Self::default();
+        for (key, v) in map {
+            let expected = |what: &str, t: &str| format!("{key} must be {what}, got {t}");
+            match key.as_str() {
+                "format" => {
+                    let name = v.clone().into_string().map_err(|t| expected("a string", t))?;
+                    options.format = Some(format_by_name(&name)?);
+                },
+                "quality" => {
+                    let q = v.as_int().map_err(|t| expected("a number", t))?;
+                    options.quality = Some(quality(q)?);
+                },
+                "compression" => {
+                    options.compression = Some(if let Ok(level) = v.as_int() {
+                        u8::try_from(level).ok().filter(|l| *l <= 9).map(CompressionType::Level)
+                            .ok_or_else(|| format!("compression must be 0 to 9, got {level}"))?
+                    } else {
+                        let name = v.clone().into_string()
+                            .map_err(|t| expected("a level or a name", t))?;
+                        match name.as_str() {
+                            "none" => CompressionType::Uncompressed,
+                            "fast" => CompressionType::Fast,
+                            "default" => CompressionType::Default,
+                            "best" => CompressionType::Best,
+                            _ => return Err(format!(
+                                "compression must be none, fast, default or best, got \"{name}\""
+                            )),
+                        }
+                    });
+                },
+                "lossless" => {
+                    options.lossless = Some(v.as_bool().map_err(|t| expected("a bool", t))?);
+                },
+                "progressive" => {
+                    options.progressive = v.as_bool().map_err(|t| expected("a bool", t))?;
+                },
+                "metadata" => {
+                    options.metadata = v.as_bool().map_err(|t| expected("a bool", t))?;
+                },
+                _ => return Err(format!("unknown option \"{key}\"")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// How save_max_bytes may get an image below the limit.
+#[derive(Debug, Clone)]
+pub struct FitOptions {
+    /// Make the image smaller once the lowest quality is not small enough.
+    pub downscale: bool,
+    pub min_quality: u8,
+    /// Resize filter for downscaling, "" is the configured one.
+    pub filter: String,
+    pub metadata: bool,
+}
+
+/// What save_max_bytes settled on.
+#[derive(Debug, Clone)]
+pub struct Fitted {
+    pub format: ImageFormat,
+    pub quality: Option<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub size: usize,
+}
+
+impl Default for FitOptions {
+    fn default() -> Self {
+        Self {
+            downscale: false,
+            min_quality: 1,
+            filter: String::new(),
+            metadata: false,
+        }
+    }
+}
+
+impl FitOptions {
+    /// From the map given to save_max_bytes, unknown keys are an error.
+    pub fn from_map(map: &Map) -> Result<Self, String> {
+        let mut options = Self::default();
+        for (key, v) in map {
+            let expected = |what: &str, t: &str| format!("{key} must be {what}, got {t}");
+            match key.as_str() {
+                "downscale" => {
+                    options.downscale = v.as_bool().map_err(|t| expected("a bool", t))?;
+                },
+                "min_quality" => {
+                    let q = v.as_int().map_err(|t| expected("a number", t))?;
+                    options.min_quality = quality(q)?;
+                },
+                "filter" => {
+                    options.filter = v.clone().into_string().map_err(|t| expected("a string", t))?;
+                },
+                "metadata" => {
+                    options.metadata = v.as_bool().map_err(|t| expected("a bool", t))?;
+                },
+                _ => return Err(format!("unknown option \"{key}\"")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl Fitted {
+    /// A map for the script, quality is () for formats without one.
+    pub fn to_value(&self) -> Value {
+        Value::Map(vec![
+            ("format".to_string(), Value::Str(format_name(self.format).to_string())),
+            ("quality".to_string(), self.quality.map_or(Value::Unit, |q| Value::Int(q as i64))),
+            ("width".to_string(), Value::Int(self.width as i64)),
+            ("height".to_string(), Value::Int(self.height as i64)),
+            ("bytes".to_string(), Value::Int(self.size as i64)),
+        ])
+    }
+}
+
+/// Write the image in the format of the options, or else of the extension of the path.
+pub fn write_image(
+    img: &DynamicImage, path: &Path, options: &SaveOptions, jpeg_quality: u8,
+    origin: Option<&Origin>,
+) -> Result<(), String> {
+    let format = match options.format {
+        Some(format) => format,
+        None => format_of_path(path)?,
+    };
+    let bytes = encode(img, format, options, jpeg_quality, origin)?;
+    std::fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+/// Write the image in at most max_bytes, in the format or else the one of the extension.
+pub fn write_max_bytes(
+    img: &DynamicImage, path: &Path, format: Option<ImageFormat>, max_bytes: usize,
+    fit: &FitOptions, filter: ResizeFilter, origin: Option<&Origin>,
+) -> Result<Fitted, String> {
+    let format = match format {
+        Some(format) => format,
+        None => format_of_path(path)?,
+    };
+    let (fitted, bytes) = fit_max_bytes(img, format, max_bytes, fit, filter, origin)?;
+    std::fs::write(path, bytes).map_err(|e| e.to_string())?;
+    Ok(fitted)
+}
+
+/// The highest quality that fits, at full size or else, when allowed, at the largest size
+/// tried that fits. Formats without a quality are only downscaled.
+pub fn fit_max_bytes(
+    img: &DynamicImage, format: ImageFormat, max_bytes: usize, fit: &FitOptions,
+    filter: ResizeFilter, origin: Option<&Origin>,
+) -> Result<(Fitted, Vec<u8>), String> {
+    let (w, h) = (img.width() as f64, img.height() as f64);
+    let mut scale = 1.0;
+    loop {
+        let resized;
+        let current = if scale < 1.0 {
+            let (sw, sh) = ((w * scale).round().max(1.0), (h * scale).round().max(1.0));
+            resized = img.resize(sw as u32, sh as u32, filter);
+            &resized
+        } else {
+            img
+        };
+        let (quality, bytes) = best_quality(current, format, max_bytes, fit, origin)?;
+        if bytes.len() <= max_bytes {
+            let fitted = Fitted {
+                format, quality,
+                width: current.width(),
+                height: current.height(),
+                size: bytes.len(),
+            };
+            return Ok((fitted, bytes));
+        }
+        if !fit.downscale || current.width().max(current.height()) <= MIN_SIDE {
+            return Err(format!(
+                "could not get it below {max_bytes} bytes, the smallest was {} bytes", bytes.len()
+            ));
+        }
+        // the size goes about with the amount of pixels
+        scale *= (max_bytes as f64 / bytes.len() as f64).sqrt().clamp(0.5, 0.9);
+    }
+}
+
+/// Binary search for the highest quality that fits, or else the encoding at the lowest quality.
+fn best_quality(
+    img: &DynamicImage, format: ImageFormat, max_bytes: usize, fit: &FitOptions,
+    origin: Option<&Origin>,
+) -> Result<(Option<u8>, Vec<u8>), String> {
+    let encode_at = |quality| {
+        let options = SaveOptions { quality, metadata: fit.metadata, ..Default::default() };
+        encode(img, format, &options, 100, origin)
+    };
+    if !has_quality(format) {
+        return Ok((None, encode_at(None)?));
+    }
+    let (mut lo, mut hi) = (fit.min_quality, 100);
+    let mut best = None;
+    let mut last = Vec::new();
+    while lo <= hi {
+        let q = lo + (hi - lo) / 2;
+        let bytes = encode_at(Some(q))?;
+        if bytes.len() <= max_bytes {
+            best = Some((Some(q), bytes));
+            lo = q + 1;
+        } else {
+            last = bytes;
+            hi = q - 1;
+        }
+    }
+    // nothing fits, then the last one tried was at the lowest quality
+    Ok(best.unwrap_or((Some(fit.min_quality), last)))
+}
+
+/// The image as it would be written to a file, options that do not apply to the format
+/// are an error.
+pub fn encode(
+    img: &DynamicImage, format: ImageFormat, options: &SaveOptions, jpeg_quality: u8,
+    origin: Option<&Origin>,
+) -> Result<Vec<u8>, String> {
+    let name = format_name(format);
+    let only = |given: bool, option: &str, formats: &str| if given {
+        Err(format!("{option} only applies to {formats}, not to {name}"))
+    } else {
+        Ok(())
+    };
+    only(options.quality.is_some() && !has_quality(format), "quality", "jpeg, webp and avif")?;
+    only(options.compression.is_some() && format != ImageFormat::Png, "compression", "png")?;
+    only(options.progressive && format != ImageFormat::Jpeg, "progressive", "jpeg")?;
+    only(options.lossless.is_some() && format != ImageFormat::WebP, "lossless", "webp")?;
+
+    let metadata = origin.filter(|_| options.metadata);
+    let mut bytes = Vec::new();
+    let res = match format {
+        ImageFormat::Jpeg if options.progressive => {
+            let quality = options.quality.unwrap_or(jpeg_quality);
+            return encode_progressive(img, quality, metadata);
+        },
+        ImageFormat::Jpeg => {
+            let quality = options.quality.unwrap_or(jpeg_quality);
+            let encoder = JpegEncoder::new_with_quality(&mut bytes, quality);
+            // jpeg has no alpha channel
+            DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_with_encoder(with_metadata(encoder, metadata, name)?)
+        },
+        ImageFormat::Png => {
+            let compression = options.compression.unwrap_or_default();
+            let filter = FilterType::default();
+            let encoder = PngEncoder::new_with_quality(&mut bytes, compression, filter);
+            img.write_with_encoder(with_metadata(encoder, metadata, name)?)
+        },
+        ImageFormat::WebP if options.quality.is_some() || options.lossless == Some(false) => {
+            if options.lossless == Some(true) {
+                return Err("lossless webp has no quality".to_string());
+            }
+            let quality = options.quality.unwrap_or(jpeg_quality);
+            return encode_lossy_webp(img, quality, metadata);
+        },
+        ImageFormat::WebP => {
+            let encoder = WebPEncoder::new_lossless(&mut bytes);
+            // the encoder only takes 8 bit colours
+            let img = if img.color().has_alpha() {
+                DynamicImage::ImageRgba8(img.to_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(img.to_rgb8())
+            };
+            img.write_with_encoder(with_metadata(encoder, metadata, name)?)
+        },
+        ImageFormat::Avif => {
+            let encoder = match options.quality {
+                Some(quality) => AvifEncoder::new_with_speed_quality(&mut bytes, 4, quality),
+                None => AvifEncoder::new(&mut bytes),
+            };
+            img.write_with_encoder(with_metadata(encoder, metadata, name)?)
+        },
+        _ => {
+            only(options.metadata, "metadata", "jpeg, png, webp and avif")?;
+            img.write_to(&mut Cursor::new(&mut bytes), format)
+        },
+    };
+    res.map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+fn encode_progressive(img: &DynamicImage, quality: u8, origin: Option<&Origin>)
+    -> Result<Vec<u8>, String>
+{
+    let (w, h) = match (u16::try_from(img.width()), u16::try_from(img.height())) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => return Err(format!(
+            "jpeg can be at most 65535x65535, got {}x{}", img.width(), img.height()
+        )),
+    };
+    let mut bytes = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut bytes, quality);
+    encoder.set_progressive(true);
+    if let Some(origin) = origin {
+        if let Some(icc) = &origin.metadata.icc {
+            encoder.add_icc_profile(icc).map_err(|e| e.to_string())?;
+        }
+        if let Some(exif) = &origin.metadata.exif {
+            encoder.add_exif_metadata(exif).map_err(|e| e.to_string())?;
+        }
+    }
+    // jpeg has no alpha channel
+    encoder.encode(&img.to_rgb8(), w, h, jpeg_encoder::ColorType::Rgb)
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// The encoder of image only writes lossless webp, libwebp does the lossy ones.
+fn encode_lossy_webp(img: &DynamicImage, quality: u8, origin: Option<&Origin>)
+    -> Result<Vec<u8>, String>
+{
+    if origin.is_some_and(|o| o.metadata.icc.is_some() || o.metadata.exif.is_some()) {
+        return Err("lossy webp can not keep the metadata".to_string());
+    }
+    let (w, h) = (img.width(), img.height());
+    let memory = if img.color().has_alpha() {
+        webp::Encoder::from_rgba(&img.to_rgba8(), w, h).encode(quality as f32)
+    } else {
+        webp::Encoder::from_rgb(&img.to_rgb8(), w, h).encode(quality as f32)
+    };
+    Ok(memory.to_vec())
+}
+
+fn has_quality(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Avif)
+}
+
+/// Formats by one of their extensions, only the ones that can be written.
+pub fn format_by_name(name: &str) -> Result<ImageFormat, String> {
+    let format = ImageFormat::from_extension(name)
+        .ok_or_else(|| format!("unknown format \"{name}\""))?;
+    if !format.can_write() || !format.writing_enabled() {
+        return Err(format!("{} can not be written", format_name(format)));
+    }
+    Ok(format)
+}
+
+fn format_of_path(path: &Path) -> Result<ImageFormat, String> {
+    let ext = path.extension()
+        .and_then(|e| e.to_str())
+        .ok_or("no extension to take the format from, give a format instead")?;
+    format_by_name(ext)
+}
+
+fn format_name(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("this format")
+}
+
+fn with_metadata<E: ImageEncoder>(mut encoder: E, origin: Option<&Origin>, name: &str)
+    -> Result<E, String>
+{
+    let Some(origin) = origin else {
+        return Ok(encoder);
+    };
+    if let Some(icc) = &origin.metadata.icc {
+        encoder.set_icc_profile(icc.clone())
+            .map_err(|_| format!("{name} can not keep the ICC profile"))?;
+    }
+    if let Some(exif) = &origin.metadata.exif {
+        encoder.set_exif_metadata(exif.clone())
+            .map_err(|_| format!("{name} can not keep the EXIF data"))?;
+    }
+    Ok(encoder)
+}
+
+fn quality(q: i64) -> Result<u8, String> {
+    u8::try_from(q).ok()
+        .filter(|q| (1..=100).contains(q))
+        .ok_or_else(|| format!("quality must be between 1 and 100, got {q}"))
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+    use crate::buffers::Metadata;
+    use image::{ RgbImage, RgbaImage };
+    use rhai::Engine;
+
+    /// Noise does not compress well, so quality makes a difference.
+    fn noise(w: u32, h: u32) -> DynamicImage {
+        let mut seed = 1u32;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(w, h, |_, _| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            image::Rgb([(seed >> 24) as u8, (seed >> 16) as u8, (seed >> 8) as u8])
+        }))
+    }
+
+    fn options(map: &str) -> Result<SaveOptions, String> {
+        SaveOptions::from_map(&Engine::new().eval::<Map>(map).unwrap())
+    }
+
+    #[test]
+    fn test_from_map(){
+        let o = options(r#"#{ format: "jpg", quality: 85, progressive: false }"#).unwrap();
+        assert_eq!(o.format, Some(ImageFormat::Jpeg));
+        assert_eq!(o.quality, Some(85));
+        let o = options(r#"#{ compression: "best", metadata: true }"#).unwrap();
+        assert_eq!(o.compression, Some(CompressionType::Best));
+        assert!(o.metadata);
+        let o = options("#{ compression: 3 }").unwrap();
+        assert_eq!(o.compression, Some(CompressionType::Level(3)));
+
+        assert!(options("#{ quality: 0 }").is_err());
+        assert!(options(r#"#{ quality: "high" }"#).is_err());
+        assert!(options(r#"#{ format: "xyz" }"#).is_err());
+        assert!(options("#{ progressive: true }").unwrap().progressive);
+        assert!(options("#{ qualty: 85 }").is_err());
+    }
+
+    #[test]
+    fn test_encode(){
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(4, 4));
+        let jpeg = |o: &SaveOptions| encode(&img, ImageFormat::Jpeg, o, 90, None);
+        assert!(jpeg(&SaveOptions::default()).unwrap().starts_with(&[0xff, 0xd8]));
+        let best = SaveOptions { compression: Some(CompressionType::Best), ..Default::default() };
+        assert!(jpeg(&best).is_err());
+        let progressive = SaveOptions { progressive: true, ..Default::default() };
+        // a progressive frame starts with SOF2 instead of SOF0
+        let sof2 = |bytes: Vec<u8>| bytes.windows(2).any(|m| m == [0xff, 0xc2]);
+        assert!(sof2(jpeg(&progressive).unwrap()));
+        assert!(!sof2(jpeg(&SaveOptions::default()).unwrap()));
+        assert!(encode(&img, ImageFormat::Png, &progressive, 90, None).is_err());
+        let png = encode(&img, ImageFormat::Png, &SaveOptions::default(), 90, None).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+        let lossy = SaveOptions { lossless: Some(false), ..Default::default() };
+        let webp = encode(&img, ImageFormat::WebP, &lossy, 90, None).unwrap();
+        assert!(webp.starts_with(b"RIFF") && &webp[8..12] == b"WEBP");
+        let both = SaveOptions { lossless: Some(true), quality: Some(80), ..Default::default() };
+        assert!(encode(&img, ImageFormat::WebP, &both, 90, None).is_err());
+
+        let origin = Origin {
+            path: "a.jpg".into(),
+            format: Some(ImageFormat::Jpeg),
+            metadata: Metadata { icc: None, exif: Some(b"Exif\0\0MM".to_vec()) },
+        };
+        let keep = SaveOptions { metadata: true, ..Default::default() };
+        let bmp = encode(&img, ImageFormat::Bmp, &keep, 90, Some(&origin));
+        assert!(bmp.is_err());
+
+        assert_eq!(format_of_path(Path::new("a/b.JPEG")), Ok(ImageFormat::Jpeg));
+        assert!(format_of_path(Path::new("a/b")).is_err());
+    }
+
+    #[test]
+    fn test_fit_max_bytes(){
+        let img = noise(64, 64);
+        let fit = FitOptions::default();
+        let filter = ResizeFilter::Triangle;
+        let best = encode(&img, ImageFormat::Jpeg, &SaveOptions::default(), 100, None).unwrap();
+        let (f, _) = fit_max_bytes(&img, ImageFormat::Jpeg, best.len(), &fit, filter, None)
+            .unwrap();
+        assert_eq!((f.quality, f.width, f.size), (Some(100), 64, best.len()));
+
+        let max = best.len() / 2;
+        let (f, bytes) = fit_max_bytes(&img, ImageFormat::Jpeg, max, &fit, filter, None).unwrap();
+        assert!(bytes.len() <= max && f.size == bytes.len());
+        let q = f.quality.unwrap();
+        assert!(q < 100);
+        let higher = SaveOptions { quality: Some(q + 1), ..Default::default() };
+        assert!(encode(&img, ImageFormat::Jpeg, &higher, 100, None).unwrap().len() > max);
+
+        assert!(fit_max_bytes(&img, ImageFormat::Jpeg, 500, &fit, filter, None).is_err());
+        let fit = FitOptions { downscale: true, min_quality: 50, ..Default::default() };
+        let (f, bytes) = fit_max_bytes(&img, ImageFormat::Jpeg, 1500, &fit, filter, None)
+            .unwrap();
+        assert!(bytes.len() <= 1500 && f.width < 64 && f.width == f.height);
+        assert!(f.quality.unwrap() >= 50);
+
+        let fit = FitOptions::default();
+        let best = SaveOptions { quality: Some(100), ..Default::default() };
+        let max = encode(&img, ImageFormat::WebP, &best, 100, None).unwrap().len() / 2;
+        let (f, bytes) = fit_max_bytes(&img, ImageFormat::WebP, max, &fit, filter, None).unwrap();
+        assert!(f.quality.is_some_and(|q| q < 100) && f.width == 64);
+        assert!(bytes.len() <= max && bytes.starts_with(b"RIFF"));
+
+        let fit = FitOptions { downscale: true, ..Default::default() };
+        let (f, _) = fit_max_bytes(&img, ImageFormat::Png, 8000, &fit, filter, None).unwrap();
+        assert!(f.quality.is_none() && f.width < 64);
+    }
+}
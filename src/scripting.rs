@@ -1,11 +1,38 @@
-use rhai::Engine;
-use sdl2::keyboard::Mod;
+use crate::{
+    prompt::PromptKind,
+    buffers::PathPart,
+    save::{ SaveOptions, FitOptions, format_by_name },
+};
+
+use image::ImageFormat;
+use rhai::{ Engine, EvalAltResult, Dynamic, Array, FnPtr, NativeCallContext };
+use sdl2::{
+    keyboard::Mod,
+    mouse::MouseState,
+};
 
 use std::{
-    sync::mpsc,
+    sync::{ mpsc, Arc, atomic::{ AtomicU64, Ordering } },
     io::Write,
+    rc::Rc,
+    cell::RefCell,
+    collections::HashMap,
 };
 
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub id: u64,
+    pub msg: HostMsg,
+    /// Whether the script waits for the reply.
+    pub wait: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub id: u64,
+    pub result: Result<Value, String>,
+}
+
 #[derive(Debug, Clone)]
 pub enum HostMsg {
     Kill,
@@ -17,6 +44,9 @@ pub enum HostMsg {
     DrawRectXY(RectXY),
     Crop(i64, i64, i64, i64, i64, i64),
     Save(i64, String),
+    SaveWith(i64, String, SaveOptions),
+    SaveMaxBytes(i64, String, i64, Option<ImageFormat>, FitOptions),
+    Load(i64, String),
     FlipH(i64, i64),
     FlipV(i64, i64),
     Rot90(i64, i64),
@@ -41,6 +71,26 @@ pub enum HostMsg {
     Shown,
     Create(i64, i64),
     Copy(i64, i64, i64, i64),
+    Undo(i64),
+    Redo(i64),
+    HistoryLen(i64),
+    PathOf(i64, PathPart),
+    Zoom(f64),
+    Pan(i64, i64),
+    ZoomToFit,
+    ZoomToRect(RectXY),
+    SelectDrag,
+    Prompt(String, PromptKind, Option<Value>),
+    SetRenderFilter(String),
+    SetPixelGrid(bool),
+    SetStatus(String),
+    OverlayText(Vec<String>),
+    ClearOverlay,
+    Bind(String, String, String),
+    EnterMode(String),
+    NextBinding,
+    Palette,
+    Message(String),
     Repeat,
 }
 
@@ -67,22 +117,96 @@ pub struct RectXY {
 }
 
 impl RectXY {
-    fn new(px: i32, py: i32, qx: i32, qy: i32) -> Self {
+    pub fn new(px: i32, py: i32, qx: i32, qy: i32) -> Self {
         Self{ px, py, qx, qy }
     }
 }
 
 #[derive(Debug, Clone)]
-pub enum RhaiMsg {
-    Killed,
-    Input(Input),
+pub enum Value {
+    Unit,
     Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+    Input(Input),
+    WH(WH),
+}
+
+impl Value {
+    pub fn into_dynamic(self) -> Dynamic {
+        match self {
+            Value::Unit => Dynamic::UNIT,
+            Value::Int(i) => i.into(),
+            Value::Float(f) => f.into(),
+            Value::Bool(b) => b.into(),
+            Value::Str(s) => s.into(),
+            Value::Array(a) => a.into_iter().map(Value::into_dynamic).collect::<Vec<_>>().into(),
+            Value::Map(m) => m.into_iter()
+                .map(|(k, v)| (k.into(), v.into_dynamic()))
+                .collect::<rhai::Map>()
+                .into(),
+            Value::Input(i) => Dynamic::from(i),
+            Value::WH(wh) => Dynamic::from(wh),
+        }
+    }
+}
+
+macro_rules! value_into {
+    ($t: ty, $variant: ident, $what: expr) => {
+        impl TryFrom<Value> for $t {
+            type Error = String;
+
+            fn try_from(v: Value) -> Result<Self, String> {
+                match v {
+                    Value::$variant(x) => Ok(x),
+                    v => Err(format!("Editimg: expected {} from host but received {v:?}", $what)),
+                }
+            }
+        }
+    }
+}
+
+value_into!(i64, Int, "a number");
+value_into!(f64, Float, "a float");
+value_into!(bool, Bool, "a bool");
+value_into!(String, Str, "a string");
+value_into!(Vec<Value>, Array, "an array");
+value_into!(Input, Input, "input");
+value_into!(WH, WH, "a size");
+
+impl TryFrom<Value> for () {
+    type Error = String;
+
+    fn try_from(v: Value) -> Result<Self, String> {
+        match v {
+            Value::Unit => Ok(()),
+            v => Err(format!("Editimg: expected nothing from the host but received {v:?}")),
+        }
+    }
+}
+
+impl TryFrom<Value> for Dynamic {
+    type Error = String;
+
+    fn try_from(v: Value) -> Result<Self, String> {
+        Ok(v.into_dynamic())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Input {
     pub is_click: bool,
+    pub is_drag: bool,
+    pub is_release: bool,
     pub key: String,
+    pub text: String,
+    pub button: String,
+    pub left_down: bool,
+    pub middle_down: bool,
+    pub right_down: bool,
     pub u: f32,
     pub v: f32,
     pub x: i32,
@@ -97,8 +221,31 @@ pub struct Input {
 impl Input {
     pub fn click(c: (f32, f32, i32, i32), key: String) -> Self {
         Self {
-            is_click: true, key, u: c.0, v: c.1, x: c.2, y: c.3,
-            shift: false, control: false, alt: false, nummod: false, capsmod: false,
+            is_click: true,
+            left_down: key == "left", middle_down: key == "middle", right_down: key == "right",
+            button: key.clone(), key,
+            u: c.0, v: c.1, x: c.2, y: c.3,
+            ..Self::none()
+        }
+    }
+
+    pub fn drag(c: (f32, f32, i32, i32), state: MouseState) -> Self {
+        let button = if state.left() { "left" }
+            else if state.right() { "right" }
+            else { "middle" };
+        Self {
+            is_drag: true, key: "drag".to_string(), button: button.to_string(),
+            left_down: state.left(), middle_down: state.middle(), right_down: state.right(),
+            u: c.0, v: c.1, x: c.2, y: c.3,
+            ..Self::none()
+        }
+    }
+
+    pub fn release(c: (f32, f32, i32, i32), button: String) -> Self {
+        Self {
+            is_release: true, key: "release".to_string(), button,
+            u: c.0, v: c.1, x: c.2, y: c.3,
+            ..Self::none()
         }
     }
 
@@ -110,13 +257,44 @@ impl Input {
         let capsmod = keymod.contains(Mod::CAPSMOD);
 
         Self {
-            is_click: false, key, u: 0.0, v: 0.0, x: 0, y: 0,
-            shift, control, alt, nummod, capsmod,
+            key, shift, control, alt, nummod, capsmod,
+            ..Self::none()
+        }
+    }
+
+    pub fn text(text: String) -> Self {
+        Self {
+            key: "text".to_string(), text,
+            ..Self::none()
+        }
+    }
+
+    pub fn termination() -> Self {
+        Self {
+            key: "termination".to_string(),
+            ..Self::none()
+        }
+    }
+
+    fn none() -> Self {
+        Self {
+            is_click: false, is_drag: false, is_release: false, key: String::new(),
+            text: String::new(), button: String::new(),
+            left_down: false, middle_down: false, right_down: false,
+            u: 0.0, v: 0.0, x: 0, y: 0,
+            shift: false, control: false, alt: false, nummod: false, capsmod: false,
         }
     }
 
     fn get_is_click(&mut self) -> bool { self.is_click }
+    fn get_is_drag(&mut self) -> bool { self.is_drag }
+    fn get_is_release(&mut self) -> bool { self.is_release }
     fn get_key(&mut self) -> String { self.key.clone() }
+    fn get_text(&mut self) -> String { self.text.clone() }
+    fn get_button(&mut self) -> String { self.button.clone() }
+    fn get_left_down(&mut self) -> bool { self.left_down }
+    fn get_middle_down(&mut self) -> bool { self.middle_down }
+    fn get_right_down(&mut self) -> bool { self.right_down }
     fn get_u(&mut self) -> f64 { self.u as f64 }
     fn get_v(&mut self) -> f64 { self.v as f64 }
     fn get_x(&mut self) -> i64 { self.x as i64 }
@@ -139,14 +317,39 @@ impl WH {
     fn get_h(&mut self) -> i64 { self.h }
 }
 
+type RhaiRes<T> = Result<T, Box<EvalAltResult>>;
+
+#[derive(Clone)]
 pub struct HostPortals {
-    pub to_host: mpsc::Sender<HostMsg>,
-    pub from_host: spmc::Receiver<RhaiMsg>,
+    to_host: mpsc::Sender<Request>,
+    from_host: spmc::Receiver<Reply>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl HostPortals {
+    /// Send a request to the host and wait for its reply.
+    pub fn call<T: TryFrom<Value, Error = String>>(&self, msg: HostMsg) -> RhaiRes<T> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.to_host.send(Request { id, msg, wait: true }).map_err(|_| host_closed())?;
+        match self.from_host.recv() {
+            Ok(Reply { id: rid, result }) if rid == id => Ok(T::try_from(result?)?),
+            Ok(Reply { id: rid, .. }) => {
+                Err(format!("Editimg: expected reply to request {id} but received {rid}").into())
+            },
+            Err(_) => Err(host_closed()),
+        }
+    }
+
+    /// Send a request to the host without waiting, it may not even be running yet.
+    pub fn tell(&self, msg: HostMsg) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let _ = self.to_host.send(Request { id, msg, wait: false });
+    }
 }
 
 pub struct RhaiPortals {
-    pub from_rhai: mpsc::Receiver<HostMsg>,
-    pub to_rhai: spmc::Sender<RhaiMsg>,
+    pub from_rhai: mpsc::Receiver<Request>,
+    pub to_rhai: spmc::Sender<Reply>,
 }
 
 pub fn create_channels() -> (HostPortals, RhaiPortals) {
@@ -154,7 +357,7 @@ pub fn create_channels() -> (HostPortals, RhaiPortals) {
     let (to_rhai, from_host) = spmc::channel();
     (
         HostPortals {
-            to_host, from_host,
+            to_host, from_host, next_id: Arc::new(AtomicU64::new(0)),
         },
         RhaiPortals {
             from_rhai, to_rhai,
@@ -162,21 +365,112 @@ pub fn create_channels() -> (HostPortals, RhaiPortals) {
     )
 }
 
-pub fn construct_rhai_engine(host_portals: HostPortals) -> Engine {
+/// An argument the palette asks for before calling a function.
+#[derive(Debug, Clone, Copy)]
+pub enum Arg {
+    Src,
+    Dst,
+    Int(&'static str),
+    Float(&'static str),
+    Bool(&'static str),
+    Text(&'static str),
+    Filter,
+    RenderFilter,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub args: &'static [Arg],
+}
+
+const fn cmd(name: &'static str, description: &'static str, args: &'static [Arg]) -> Command {
+    Command { name, description, args }
+}
+
+const RESIZE: &[Arg] = &[Arg::Src, Arg::Dst, Arg::Int("w"), Arg::Int("h"), Arg::Filter];
+const THUMBNAIL: &[Arg] = &[Arg::Src, Arg::Dst, Arg::Int("w"), Arg::Int("h")];
+
+/// Functions of construct_rhai_engine that make sense to call from the palette, save_with is
+/// missing as the palette can not ask for its map of options.
+pub const COMMANDS: &[Command] = {
+    use Arg::*;
+    &[
+        cmd("show", "show a buffer", &[Src]),
+        cmd("show_next", "show the next buffer", &[]),
+        cmd("show_prev", "show the previous buffer", &[]),
+        cmd("crop", "take the section from p to q", &[
+            Src, Dst, Int("px"), Int("py"), Int("qx"), Int("qy"),
+        ]),
+        cmd("save", "save a buffer to a file", &[Src, Text("path")]),
+        cmd("save_max_bytes", "save a buffer in at most so many bytes", &[
+            Src, Text("path"), Int("bytes"), Text("format"),
+        ]),
+        cmd("load", "load a file into a new buffer", &[Text("path")]),
+        cmd("load_into", "load a file into a buffer", &[Dst, Text("path")]),
+        cmd("path_of", "path of the file a buffer was loaded from", &[Src]),
+        cmd("stem_of", "file name without extension of a buffer", &[Src]),
+        cmd("ext_of", "extension of the file of a buffer", &[Src]),
+        cmd("dir_of", "directory of the file of a buffer", &[Src]),
+        cmd("create", "create an empty buffer", &[Int("w"), Int("h")]),
+        cmd("copy", "copy a buffer onto another", &[Src, Dst, Int("x"), Int("y")]),
+        cmd("fliph", "flip horizontally", &[Src, Dst]),
+        cmd("flipv", "flip vertically", &[Src, Dst]),
+        cmd("rotate90", "rotate 90 degrees clockwise", &[Src, Dst]),
+        cmd("rotate180", "rotate 180 degrees", &[Src, Dst]),
+        cmd("rotate270", "rotate 90 degrees counter clockwise", &[Src, Dst]),
+        cmd("invert", "invert the colours", &[Src, Dst]),
+        cmd("grayscale", "remove the colours", &[Src, Dst]),
+        cmd("blur", "gaussian blur", &[Src, Dst, Float("sigma")]),
+        cmd("unsharpen", "sharpen", &[Src, Dst, Float("sigma"), Int("threshold")]),
+        cmd("adjust_contrast", "change the contrast", &[Src, Dst, Float("contrast")]),
+        cmd("brighten", "change the brightness", &[Src, Dst, Int("value")]),
+        cmd("huerotate", "rotate the hue", &[Src, Dst, Int("degrees")]),
+        cmd("resize", "resize keeping the aspect ratio", RESIZE),
+        cmd("resize_exact", "resize to exactly w x h", RESIZE),
+        cmd("resize_fill", "resize and crop to fill w x h", RESIZE),
+        cmd("thumbnail", "fast downscale keeping the aspect ratio", THUMBNAIL),
+        cmd("thumbnail_exact", "fast downscale to exactly w x h", THUMBNAIL),
+        cmd("undo", "undo the last change to a buffer", &[Src]),
+        cmd("redo", "redo the last undone change to a buffer", &[Src]),
+        cmd("zoom", "zoom in by a factor", &[Float("factor")]),
+        cmd("zoom_to_fit", "show the whole image", &[]),
+        cmd("zoom_to_rect", "show the section from p to q", &[
+            Int("px"), Int("py"), Int("qx"), Int("qy"),
+        ]),
+        cmd("pan", "move the image", &[Int("dx"), Int("dy")]),
+        cmd("render_filter", "scaling used to show images", &[RenderFilter]),
+        cmd("pixel_grid", "draw a grid around pixels", &[Bool("on")]),
+        cmd("repeat", "repeat the last image function", &[]),
+        cmd("kill", "quit editimg", &[]),
+    ]
+};
+
+/// Without echo printed text only goes to the host, which may keep it for later.
+pub fn construct_rhai_engine(host_portals: HostPortals, echo: bool) -> Engine {
     let mut engine = Engine::new();
     engine.set_max_expr_depths(50, 50);
-    engine.on_print(|msg| {
-        print!("{msg}");
-        let _ = std::io::stdout().flush();
+    // printed text also ends up in the status bar of the window, without waiting for the host
+    // as the library prints before it runs
+    let hp_print = host_portals.clone();
+    engine.on_print(move |msg| {
+        if echo {
+            print!("{msg}");
+            let _ = std::io::stdout().flush();
+        }
+        hp_print.tell(HostMsg::Message(msg.to_string()));
     });
 
-    let HostPortals{
-        to_host, from_host,
-    } = host_portals;
-
     engine.register_type_with_name::<Input>("Input")
         .register_get("is_click", Input::get_is_click)
+        .register_get("is_drag", Input::get_is_drag)
+        .register_get("is_release", Input::get_is_release)
         .register_get("key", Input::get_key)
+        .register_get("text", Input::get_text)
+        .register_get("button", Input::get_button)
+        .register_get("left_down", Input::get_left_down)
+        .register_get("middle_down", Input::get_middle_down)
+        .register_get("right_down", Input::get_right_down)
         .register_get("u", Input::get_u)
         .register_get("v", Input::get_v)
         .register_get("x", Input::get_x)
@@ -190,195 +484,375 @@ pub fn construct_rhai_engine(host_portals: HostPortals) -> Engine {
         .register_get("w", WH::get_w)
         .register_get("h", WH::get_h);
 
-    let receive_err = "Editimg: rhai thread could not receive from host.";
-    let send_err = "Editimg: rhai thread could not send to host.";
-
     macro_rules! def_clones {
         ( $clonee:ident, $( $name:ident ), * ) => { $( let $name = $clonee.clone(); )* }
     }
-    def_clones!( to_host,
-        th_input, th_ruv, th_rxy, th_clear, th_wh, th_buffers_len, th_crop, th_save, th_fliph,
-        th_flipv, th_rot90, th_rot180, th_rot270, th_invert, th_grayscale, th_blur, th_unsharpen,
-        th_filter3x3, th_adjust_contrast, th_brighten, th_huerotate, th_resize, th_resize_exact,
-        th_resize_fill, th_thumbnail, th_thumbnail_exact, th_show, th_show_next, th_show_prev,
-        th_shown, th_create, th_copy, th_repeat
+    // closures can not go to the host, it refers to them by the id of their binding
+    let closures: Rc<RefCell<HashMap<i64, FnPtr>>> = Rc::default();
+    let bind_closures = closures.clone();
+    let palette_closures = closures.clone();
+    def_clones!( host_portals,
+        hp_kill, hp_input, hp_ruv, hp_rxy, hp_clear, hp_wh, hp_buffers_len, hp_crop, hp_save,
+        hp_save_with, hp_save_max_bytes, hp_save_max_bytes_o, hp_load, hp_load_into, hp_fliph,
+        hp_flipv, hp_rot90, hp_rot180, hp_rot270, hp_invert,
+        hp_grayscale, hp_blur, hp_unsharpen, hp_filter3x3, hp_adjust_contrast, hp_brighten,
+        hp_huerotate, hp_resize, hp_resize_exact, hp_resize_fill, hp_thumbnail,
+        hp_thumbnail_exact, hp_show, hp_show_next, hp_show_prev, hp_shown, hp_create, hp_copy,
+        hp_undo, hp_redo, hp_history_len, hp_path_of, hp_stem_of, hp_ext_of, hp_dir_of,
+        hp_zoom, hp_pan, hp_zoom_to_fit, hp_zoom_to_rect,
+        hp_select_drag, hp_prompt_text, hp_prompt_int, hp_prompt_int_d, hp_prompt_float,
+        hp_prompt_float_d, hp_prompt_bool, hp_prompt_bool_d, hp_prompt_choice, hp_prompt_choice_d,
+        hp_render_filter, hp_pixel_grid, hp_set_status, hp_overlay_text, hp_overlay_str,
+        hp_clear_overlay, hp_bind, hp_enter_mode, hp_run_keymap, hp_palette,
+        hp_repeat
     );
-    def_clones!( from_host,
-        fh_input, fh_wh, fh_buffers_len, fh_crop, fh_fliph, fh_flipv, fh_rotate90, fh_rotate180,
-        fh_rotate270, fh_invert, fh_grayscale, fh_blur, fh_unsharpen, fh_filter, fh_contrast,
-        fh_brighten, fh_huerotate, fh_resize, fh_resize_exact, fh_resize_fill, fh_thumbnail,
-        fh_thumbnail_exact, fh_show, fh_show_next, fh_show_prev, fh_shown, fh_create, fh_copy
-    );
-
-    macro_rules! recv_buf {
-        ($fh: ident) => {
-            if let RhaiMsg::Int(i) = $fh.recv().expect(receive_err) { i }
-            else { quit("Editimg: rhai thread expected crop buffer but received otherwise."); }
-        }
-    }
 
     use HostMsg::*;
 
     engine
-        .register_fn("kill", move || {
-            to_host.send(Kill).expect(send_err);
-        })
-        .register_fn("clear_rects", move || {
-            th_clear.clone().send(ClearRects).expect(send_err);
-        })
-        .register_fn("draw_rect_uv", move |px: f64, py: f64, qx: f64, qy: f64| {
-            th_ruv.send(DrawRectUV(RectUV::new(px as f32, py as f32, qx as f32, qy as f32)))
-                .expect(send_err);
-        })
-        .register_fn("draw_rect_xy", move |px: i64, py: i64, qx: i64, qy: i64| {
-            th_rxy.send(DrawRectXY(RectXY::new(px as i32, py as i32, qx as i32, qy as i32)))
-                .expect(send_err);
-        })
-        .register_fn("get_input_event", move || -> Input {
-            th_input.send(GetInputEvent).expect(send_err);
-            if let RhaiMsg::Input(input) = fh_input.recv().expect(receive_err) {
-                input
-            } else {
-                quit("Editimg: rhai thread expected input but received otherwise.");
+        .register_fn("kill", move || -> RhaiRes<()> {
+            hp_kill.call(Kill)
+        })
+        .register_fn("clear_rects", move || -> RhaiRes<()> {
+            hp_clear.call(ClearRects)
+        })
+        .register_fn("draw_rect_uv", move |px: f64, py: f64, qx: f64, qy: f64| -> RhaiRes<()> {
+            hp_ruv.call(DrawRectUV(RectUV::new(px as f32, py as f32, qx as f32, qy as f32)))
+        })
+        .register_fn("draw_rect_xy", move |px: i64, py: i64, qx: i64, qy: i64| -> RhaiRes<()> {
+            hp_rxy.call(DrawRectXY(RectXY::new(px as i32, py as i32, qx as i32, qy as i32)))
+        })
+        .register_fn("get_input_event", move || -> RhaiRes<Input> {
+            hp_input.call(GetInputEvent)
+        })
+        .register_fn("get_wh", move |s: i64| -> RhaiRes<WH> {
+            hp_wh.call(GetWH(s))
+        })
+        .register_fn("get_buffers_len", move || -> RhaiRes<i64> {
+            hp_buffers_len.call(GetBuffersLen)
+        })
+        .register_fn("crop",
+            move |s: i64, d: i64, px: i64, py: i64, qx: i64, qy: i64| -> RhaiRes<i64> {
+                hp_crop.call(Crop(s, d, px, py, qx, qy))
+            }
+        )
+        .register_fn("save", move |s: i64, p: String| -> RhaiRes<()> {
+            hp_save.call(Save(s, p))
+        })
+        .register_fn("save_with", move |s: i64, p: String, options: rhai::Map| -> RhaiRes<()> {
+            let options = SaveOptions::from_map(&options).map_err(|e| format!("save_with: {e}"))?;
+            hp_save_with.call(SaveWith(s, p, options))
+        })
+        .register_fn("save_max_bytes",
+            move |s: i64, p: String, bytes: i64, format: &str| -> RhaiRes<Dynamic> {
+                let format = max_bytes_format(format)?;
+                hp_save_max_bytes.call(SaveMaxBytes(s, p, bytes, format, FitOptions::default()))
             }
+        )
+        .register_fn("save_max_bytes",
+            move |s: i64, p: String, bytes: i64, format: &str, options: rhai::Map|
+                -> RhaiRes<Dynamic>
+            {
+                let format = max_bytes_format(format)?;
+                let options = FitOptions::from_map(&options)
+                    .map_err(|e| format!("save_max_bytes: {e}"))?;
+                hp_save_max_bytes_o.call(SaveMaxBytes(s, p, bytes, format, options))
+            }
+        )
+        .register_fn("load", move |p: String| -> RhaiRes<i64> {
+            hp_load.call(Load(-1, p))
+        })
+        .register_fn("load_into", move |d: i64, p: String| -> RhaiRes<i64> {
+            hp_load_into.call(Load(d, p))
+        })
+        .register_fn("fliph", move |s: i64, d: i64| -> RhaiRes<i64> {
+            hp_fliph.call(FlipH(s, d))
+        })
+        .register_fn("flipv", move |s: i64, d: i64| -> RhaiRes<i64> {
+            hp_flipv.call(FlipV(s, d))
+        })
+        .register_fn("rotate90", move |s: i64, d: i64| -> RhaiRes<i64> {
+            hp_rot90.call(Rot90(s, d))
+        })
+        .register_fn("rotate180", move |s: i64, d: i64| -> RhaiRes<i64> {
+            hp_rot180.call(Rot180(s, d))
+        })
+        .register_fn("rotate270", move |s: i64, d: i64| -> RhaiRes<i64> {
+            hp_rot270.call(Rot270(s, d))
+        })
+        .register_fn("invert", move |s: i64, d: i64| -> RhaiRes<i64> {
+            hp_invert.call(Invert(s, d))
+        })
+        .register_fn("grayscale", move |s: i64, d: i64| -> RhaiRes<i64> {
+            hp_grayscale.call(Grayscale(s, d))
         })
-        .register_fn("get_wh", move |s: i64| -> WH {
-            th_wh.send(GetWH(s)).expect(send_err);
-            let w = if let RhaiMsg::Int(i) = fh_wh.recv().expect(receive_err) {
-                i
-            } else {
-                quit("Editimg: rhai thread expected width but received otherwise.");
-            };
-            let h = if let RhaiMsg::Int(i) = fh_wh.recv().expect(receive_err) {
-                i
-            } else {
-                quit("Editimg: rhai thread expected height but received otherwise.");
-            };
-            WH {
-                w, h
+        .register_fn("blur", move |s: i64, d: i64, sigma: f64| -> RhaiRes<i64> {
+            hp_blur.call(Blur(s, d, sigma))
+        })
+        .register_fn("unsharpen",
+            move |s: i64, d: i64, sigma: f64, threshold: i64| -> RhaiRes<i64> {
+                hp_unsharpen.call(Unsharpen(s, d, sigma, threshold))
+            }
+        )
+        .register_fn("filter3x3", move |s: i64, d: i64, filter: [f64; 9]| -> RhaiRes<i64> {
+            hp_filter3x3.call(Filter3x3(s, d, filter))
+        })
+        .register_fn("adjust_contrast", move |s: i64, d: i64, c: f64| -> RhaiRes<i64> {
+            hp_adjust_contrast.call(AdjustContrast(s, d, c))
+        })
+        .register_fn("brighten", move |s: i64, d: i64, v: i64| -> RhaiRes<i64> {
+            hp_brighten.call(Brighten(s, d, v))
+        })
+        .register_fn("huerotate", move |s: i64, d: i64, v: i64| -> RhaiRes<i64> {
+            hp_huerotate.call(Huerotate(s, d, v))
+        })
+        .register_fn("resize",
+            move |s: i64, d: i64, w: i64, h: i64, f: String| -> RhaiRes<i64> {
+                hp_resize.call(Resize(s, d, w, h, f))
+            }
+        )
+        .register_fn("resize_exact",
+            move |s: i64, d: i64, w: i64, h: i64, f: String| -> RhaiRes<i64> {
+                hp_resize_exact.call(ResizeExact(s, d, w, h, f))
+            }
+        )
+        .register_fn("resize_fill",
+            move |s: i64, d: i64, w: i64, h: i64, f: String| -> RhaiRes<i64> {
+                hp_resize_fill.call(ResizeFill(s, d, w, h, f))
             }
+        )
+        .register_fn("thumbnail", move |s: i64, d: i64, w: i64, h: i64| -> RhaiRes<i64> {
+            hp_thumbnail.call(Thumbnail(s, d, w, h))
         })
-        .register_fn("get_buffers_len", move || -> i64 {
-            th_buffers_len.send(GetBuffersLen).expect(send_err);
-            recv_buf!(fh_buffers_len)
+        .register_fn("thumbnail_exact", move |s: i64, d: i64, w: i64, h: i64| -> RhaiRes<i64> {
+            hp_thumbnail_exact.call(ThumbnailExact(s, d, w, h))
         })
-        .register_fn("crop", move |s: i64, d: i64, px: i64, py: i64, qx: i64, qy: i64| -> i64 {
-            th_crop.send(Crop(s, d, px, py, qx, qy)).expect(send_err);
-            recv_buf!(fh_crop)
+        .register_fn("show", move |i: i64| -> RhaiRes<i64> {
+            hp_show.call(Show(i))
         })
-        .register_fn("save", move |s: i64, p: String| {
-            th_save.send(Save(s, p)).expect(send_err);
+        .register_fn("show_next", move || -> RhaiRes<i64> {
+            hp_show_next.call(ShowNext)
         })
-        .register_fn("fliph", move |s: i64, d: i64| {
-            th_fliph.send(FlipH(s, d)).expect(send_err);
-            recv_buf!(fh_fliph)
+        .register_fn("show_prev", move || -> RhaiRes<i64> {
+            hp_show_prev.call(ShowPrev)
         })
-        .register_fn("flipv", move |s: i64, d: i64| {
-            th_flipv.send(FlipV(s, d)).expect(send_err);
-            recv_buf!(fh_flipv)
+        .register_fn("shown", move || -> RhaiRes<i64> {
+            hp_shown.call(Shown)
         })
-        .register_fn("rotate90", move |s: i64, d: i64| {
-            th_rot90.send(Rot90(s, d)).expect(send_err);
-            recv_buf!(fh_rotate90)
+        .register_fn("create", move |w: i64, h: i64| -> RhaiRes<i64> {
+            hp_create.call(Create(w, h))
         })
-        .register_fn("rotate180", move |s: i64, d: i64| {
-            th_rot180.send(Rot180(s, d)).expect(send_err);
-            recv_buf!(fh_rotate180)
+        .register_fn("copy", move |s: i64, d: i64, x: i64, y: i64| -> RhaiRes<bool> {
+            hp_copy.call(Copy(s, d, x, y))
         })
-        .register_fn("rotate270", move |s: i64, d: i64| {
-            th_rot270.send(Rot270(s, d)).expect(send_err);
-            recv_buf!(fh_rotate270)
+        .register_fn("undo", move |i: i64| -> RhaiRes<bool> {
+            hp_undo.call(Undo(i))
         })
-        .register_fn("invert", move |s: i64, d: i64| {
-            th_invert.send(Invert(s, d)).expect(send_err);
-            recv_buf!(fh_invert)
+        .register_fn("redo", move |i: i64| -> RhaiRes<bool> {
+            hp_redo.call(Redo(i))
         })
-        .register_fn("grayscale", move |s: i64, d: i64| {
-            th_grayscale.send(Grayscale(s, d)).expect(send_err);
-            recv_buf!(fh_grayscale)
+        .register_fn("history_len", move |i: i64| -> RhaiRes<i64> {
+            hp_history_len.call(HistoryLen(i))
         })
-        .register_fn("blur", move |s: i64, d: i64, sigma: f64| {
-            th_blur.send(Blur(s, d, sigma)).expect(send_err);
-            recv_buf!(fh_blur)
+        .register_fn("path_of", move |i: i64| -> RhaiRes<String> {
+            hp_path_of.call(PathOf(i, PathPart::Path))
         })
-        .register_fn("unsharpen", move |s: i64, d: i64, sigma: f64, threshold: i64| {
-            th_unsharpen.send(Unsharpen(s, d, sigma, threshold)).expect(send_err);
-            recv_buf!(fh_unsharpen)
+        .register_fn("stem_of", move |i: i64| -> RhaiRes<String> {
+            hp_stem_of.call(PathOf(i, PathPart::Stem))
         })
-        .register_fn("filter3x3", move |s: i64, d: i64, filter: [f64; 9]| {
-            th_filter3x3.send(Filter3x3(s, d, filter)).expect(send_err);
-            recv_buf!(fh_filter)
+        .register_fn("ext_of", move |i: i64| -> RhaiRes<String> {
+            hp_ext_of.call(PathOf(i, PathPart::Ext))
         })
-        .register_fn("adjust_contrast", move |s: i64, d: i64, c: f64| {
-            th_adjust_contrast.send(AdjustContrast(s, d, c)).expect(send_err);
-            recv_buf!(fh_contrast)
+        .register_fn("dir_of", move |i: i64| -> RhaiRes<String> {
+            hp_dir_of.call(PathOf(i, PathPart::Dir))
         })
-        .register_fn("brighten", move |s: i64, d: i64, v: i64| {
-            th_brighten.send(Brighten(s, d, v)).expect(send_err);
-            recv_buf!(fh_brighten)
+        .register_fn("zoom", move |f: f64| -> RhaiRes<()> {
+            hp_zoom.call(Zoom(f))
         })
-        .register_fn("huerotate", move |s: i64, d: i64, v: i64| {
-            th_huerotate.send(Huerotate(s, d, v)).expect(send_err);
-            recv_buf!(fh_huerotate)
+        .register_fn("pan", move |dx: i64, dy: i64| -> RhaiRes<()> {
+            hp_pan.call(Pan(dx, dy))
         })
-        .register_fn("resize", move |s: i64, d: i64, w: i64, h: i64, f: String| {
-            th_resize.send(Resize(s, d, w, h, f)).expect(send_err);
-            recv_buf!(fh_resize)
+        .register_fn("zoom_to_fit", move || -> RhaiRes<()> {
+            hp_zoom_to_fit.call(ZoomToFit)
         })
-        .register_fn("resize_exact", move |s: i64, d: i64, w: i64, h: i64, f: String| {
-            th_resize_exact.send(ResizeExact(s, d, w, h, f)).expect(send_err);
-            recv_buf!(fh_resize_exact)
+        .register_fn("zoom_to_rect", move |px: i64, py: i64, qx: i64, qy: i64| -> RhaiRes<()> {
+            let r = RectXY::new(px as i32, py as i32, qx as i32, qy as i32);
+            hp_zoom_to_rect.call(ZoomToRect(r))
         })
-        .register_fn("resize_fill", move |s: i64, d: i64, w: i64, h: i64, f: String| {
-            th_resize_fill.send(ResizeFill(s, d, w, h, f)).expect(send_err);
-            recv_buf!(fh_resize_fill)
+        .register_fn("select_drag", move || -> RhaiRes<Dynamic> {
+            hp_select_drag.call(SelectDrag)
         })
-        .register_fn("thumbnail", move |s: i64, d: i64, w: i64, h: i64| {
-            th_thumbnail.send(Thumbnail(s, d, w, h)).expect(send_err);
-            recv_buf!(fh_thumbnail)
+        .register_fn("prompt_text", move |msg: String| -> RhaiRes<Dynamic> {
+            hp_prompt_text.call(Prompt(msg, PromptKind::Text, None))
         })
-        .register_fn("thumbnail_exact", move |s: i64, d: i64, w: i64, h: i64| {
-            th_thumbnail_exact.send(ThumbnailExact(s, d, w, h)).expect(send_err);
-            recv_buf!(fh_thumbnail_exact)
+        .register_fn("prompt_int", move |msg: String| -> RhaiRes<Dynamic> {
+            hp_prompt_int.call(Prompt(msg, PromptKind::Int, None))
         })
-        .register_fn("show", move |i: i64| {
-            th_show.send(Show(i)).expect(send_err);
-            recv_buf!(fh_show)
+        .register_fn("prompt_int", move |msg: String, d: i64| -> RhaiRes<Dynamic> {
+            hp_prompt_int_d.call(Prompt(msg, PromptKind::Int, Some(Value::Int(d))))
         })
-        .register_fn("show_next", move || {
-            th_show_next.send(ShowNext).expect(send_err);
-            recv_buf!(fh_show_next)
+        .register_fn("prompt_float", move |msg: String| -> RhaiRes<Dynamic> {
+            hp_prompt_float.call(Prompt(msg, PromptKind::Float, None))
         })
-        .register_fn("show_prev", move || {
-            th_show_prev.send(ShowPrev).expect(send_err);
-            recv_buf!(fh_show_prev)
+        .register_fn("prompt_float", move |msg: String, d: f64| -> RhaiRes<Dynamic> {
+            hp_prompt_float_d.call(Prompt(msg, PromptKind::Float, Some(Value::Float(d))))
         })
-        .register_fn("shown", move || {
-            th_shown.send(Shown).expect(send_err);
-            recv_buf!(fh_shown)
+        .register_fn("prompt_bool", move |msg: String| -> RhaiRes<Dynamic> {
+            hp_prompt_bool.call(Prompt(msg, PromptKind::Bool, None))
         })
-        .register_fn("create", move |w: i64, h: i64| {
-            th_create.send(Create(w, h)).expect(send_err);
-            recv_buf!(fh_create)
+        .register_fn("prompt_bool", move |msg: String, d: bool| -> RhaiRes<Dynamic> {
+            hp_prompt_bool_d.call(Prompt(msg, PromptKind::Bool, Some(Value::Bool(d))))
         })
-        .register_fn("copy", move |s: i64, d: i64, x: i64, y: i64| {
-            th_copy.send(Copy(s, d, x, y)).expect(send_err);
-            if let RhaiMsg::Int(i) = fh_copy.recv().expect(receive_err) {
-                i == 1
-            } else {
-                quit("Editimg: rhai thread expected crop buffer but received otherwise.");
+        .register_fn("prompt_choice", move |msg: String, options: Array| -> RhaiRes<Dynamic> {
+            let options = options.into_iter().map(|o| o.to_string()).collect();
+            hp_prompt_choice.call(Prompt(msg, PromptKind::Choice(options), None))
+        })
+        .register_fn("prompt_choice",
+            move |msg: String, options: Array, d: String| -> RhaiRes<Dynamic> {
+                let options = options.into_iter().map(|o| o.to_string()).collect();
+                let kind = PromptKind::Choice(options);
+                hp_prompt_choice_d.call(Prompt(msg, kind, Some(Value::Str(d))))
+            }
+        )
+        .register_fn("render_filter", move |f: String| -> RhaiRes<()> {
+            hp_render_filter.call(SetRenderFilter(f))
+        })
+        .register_fn("pixel_grid", move |on: bool| -> RhaiRes<()> {
+            hp_pixel_grid.call(SetPixelGrid(on))
+        })
+        .register_fn("set_status", move |mode: String| -> RhaiRes<()> {
+            hp_set_status.call(SetStatus(mode))
+        })
+        .register_fn("overlay_text", move |lines: Array| -> RhaiRes<()> {
+            let lines = lines.into_iter().map(|l| l.to_string()).collect();
+            hp_overlay_text.call(OverlayText(lines))
+        })
+        .register_fn("overlay_text", move |text: String| -> RhaiRes<()> {
+            hp_overlay_str.call(OverlayText(text.lines().map(str::to_string).collect()))
+        })
+        .register_fn("clear_overlay", move || -> RhaiRes<()> {
+            hp_clear_overlay.call(ClearOverlay)
+        })
+        .register_fn("bind",
+            move |mode: String, key: String, description: String, f: FnPtr| -> RhaiRes<()> {
+                let id = hp_bind.call::<i64>(Bind(mode, key, description))?;
+                bind_closures.borrow_mut().insert(id, f);
+                Ok(())
+            }
+        )
+        .register_fn("enter_mode", move |mode: String| -> RhaiRes<()> {
+            hp_enter_mode.call(EnterMode(mode))
+        })
+        .register_fn("run_keymap", move |ctx: NativeCallContext| -> RhaiRes<()> {
+            // the host answers with the binding of the next key press, until it stops
+            // or "palette" when : was typed
+            while let Ok(next) = hp_run_keymap.call::<Dynamic>(NextBinding) {
+                let res = if let Ok(id) = next.as_int() {
+                    call_binding(&ctx, &closures, id)
+                } else if next.is_string() {
+                    run_palette(&ctx, &hp_run_keymap, &closures)
+                } else {
+                    break;
+                };
+                if let Err(e) = res {
+                    let msg = format!("Rhai error: {e}\n");
+                    print!("{msg}");
+                    let _ = hp_run_keymap.call::<()>(Message(msg));
+                }
             }
+            Ok(())
         })
-        .register_fn("repeat", move || {
-            th_repeat.send(Repeat).expect(send_err);
+        .register_fn("palette", move |ctx: NativeCallContext| -> RhaiRes<()> {
+            run_palette(&ctx, &hp_palette, &palette_closures)
+        })
+        .register_fn("repeat", move || -> RhaiRes<()> {
+            hp_repeat.call(Repeat)
         })
     ;
 
     engine
 }
 
-fn quit(msg: &str) -> ! {
-    println!("{msg}");
-    std::process::exit(0)
+/// Values given with --set and -o, and the input files, for the script.
+pub fn register_cli(engine: &mut Engine, args: HashMap<String, String>, inputs: Vec<String>) {
+    engine
+        .register_fn("arg", move |name: &str, default: Dynamic| -> RhaiRes<Dynamic> {
+            match args.get(name) {
+                Some(v) => Ok(cli_value(name, v, &default)?),
+                None => Ok(default),
+            }
+        })
+        .register_fn("input_paths", move || -> Array {
+            inputs.iter().map(|p| p.clone().into()).collect()
+        });
+}
+
+/// The value as the same type as the default.
+fn cli_value(name: &str, v: &str, default: &Dynamic) -> Result<Dynamic, String> {
+    let wrong = |t: &str| format!("arg: {name} should be {t}, got \"{v}\"");
+    if default.is_int() {
+        v.parse::<i64>().map(Dynamic::from).map_err(|_| wrong("a whole number"))
+    } else if default.is_float() {
+        v.parse::<f64>().map(Dynamic::from).map_err(|_| wrong("a number"))
+    } else if default.is_bool() {
+        v.parse::<bool>().map(Dynamic::from).map_err(|_| wrong("true or false"))
+    } else {
+        Ok(v.into())
+    }
+}
+
+type Closures = Rc<RefCell<HashMap<i64, FnPtr>>>;
+
+fn call_binding(ctx: &NativeCallContext, closures: &Closures, id: i64) -> RhaiRes<()> {
+    let f = closures.borrow().get(&id).cloned();
+    if let Some(f) = f {
+        let _ = f.call_within_context::<Dynamic>(ctx, ())?;
+    }
+    Ok(())
 }
 
+/// Let the host pick a binding or a function with its arguments, then call it.
+fn run_palette(ctx: &NativeCallContext, hp: &HostPortals, closures: &Closures) -> RhaiRes<()> {
+    let chosen = hp.call::<Dynamic>(HostMsg::Palette)?;
+    if let Ok(id) = chosen.as_int() {
+        return call_binding(ctx, closures, id);
+    }
+    let Some(mut call) = chosen.try_cast::<Array>() else { return Ok(()); };
+    if call.is_empty() { return Ok(()); }
+    let name = call.remove(0).to_string();
+    let res = ctx.call_fn::<Dynamic>(&name, call)?;
+    if !res.is_unit() {
+        let msg = format!("{name}: {res}\n");
+        print!("{msg}");
+        hp.call::<()>(HostMsg::Message(msg))?;
+    }
+    Ok(())
+}
+
+/// "" takes the format from the extension of the path.
+fn max_bytes_format(name: &str) -> RhaiRes<Option<ImageFormat>> {
+    if name.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format_by_name(name).map_err(|e| format!("save_max_bytes: {e}"))?))
+}
+
+fn host_closed() -> Box<EvalAltResult> {
+    "Editimg: the host has stopped".into()
+}
+
+#[cfg(test)]
+mod tests{
+
+    use super::*;
+
+    #[test]
+    fn test_cli_value(){
+        assert_eq!(cli_value("w", "800", &Dynamic::from(1_i64)).unwrap().as_int(), Ok(800));
+        assert_eq!(cli_value("s", "1.5", &Dynamic::from(1.0_f64)).unwrap().as_float(), Ok(1.5));
+        assert_eq!(cli_value("b", "true", &Dynamic::from(false)).unwrap().as_bool(), Ok(true));
+        let out = cli_value("output", "a.png", &Dynamic::from("b.jpg")).unwrap();
+        assert_eq!(out.into_string().unwrap(), "a.png");
+        assert!(cli_value("w", "wide", &Dynamic::from(1_i64)).is_err());
+    }
+}
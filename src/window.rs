@@ -1,18 +1,34 @@
 use crate::{
     timer::Timer,
     scripting::{ RectUV, RectXY },
+    config::{ Config, Colours },
+    font,
 };
 
 use sdl2::{
     EventPump,
     video::{ Window, WindowContext },
-    render::{ Canvas, TextureCreator, Texture },
+    render::{ Canvas, TextureCreator, Texture, BlendMode },
     pixels::{ PixelFormatEnum, Color },
     rect::{ Rect, Point },
 };
 
 use image::DynamicImage;
 
+/// Displayed size of one image pixel from which on the pixel grid is drawn.
+const GRID_MIN_SCALE: f32 = 8.0;
+/// Font scale and padding of the status bar, in window pixels.
+const STATUS_SCALE: u32 = 2;
+const STATUS_PAD: u32 = 4;
+/// Space around and between the lines of text panels, in window pixels.
+const PANEL_PAD: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderFilter{
+    Nearest,
+    Linear,
+}
+
 pub struct EIWindow{
     pub canvas: Canvas<Window>,
     pub texture_creator: TextureCreator<WindowContext>,
@@ -26,21 +42,43 @@ pub struct EIWindow{
     imgh: u32,
     texw: u32,
     texh: u32,
+    zoom: f32,
+    panx: f32,
+    pany: f32,
+    filter: RenderFilter,
+    grid: bool,
+    status: Status,
+    overlay: Vec<String>,
+    prompt: Vec<String>,
+    colours: Colours,
+}
+
+/// What the status bar at the bottom of the window shows.
+#[derive(Default)]
+struct Status{
+    buffer: usize,
+    buffers: usize,
+    mode: String,
+    message: String,
+    line_done: bool,
 }
 
 impl EIWindow{
-    pub fn create(timer: &Timer) -> Result<(Self, EventPump), String>{
+    pub fn create(timer: &Timer, config: &Config) -> Result<(Self, EventPump), String>{
+        // has to be set before the window exists
+        sdl2::hint::set("SDL_VIDEO_X11_WMCLASS", &config.window.class);
         let sdl_context = sdl2::init()?;
         let video_subsystem = sdl_context.video()?;
 
         let window = video_subsystem
-            .window("editimg", 512, 512)
+            .window(&config.window.title, config.window.width, config.window.height)
             .resizable()
             .opengl()
             .build()
             .map_err(|e| e.to_string())?;
 
-        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        canvas.set_draw_color(rgb(config.colours.background));
         let texture_creator = canvas.texture_creator();
         let event_pump = sdl_context.event_pump()?;
 
@@ -59,6 +97,15 @@ impl EIWindow{
                 imgh: 1,
                 texw: 0,
                 texh: 0,
+                zoom: 1.0,
+                panx: 0.0,
+                pany: 0.0,
+                filter: RenderFilter::Linear,
+                grid: false,
+                status: Status::default(),
+                overlay: Vec::new(),
+                prompt: Vec::new(),
+                colours: config.colours.clone(),
             },
             event_pump
         ))
@@ -74,6 +121,13 @@ impl EIWindow{
         let imgw = plain.width();
         let imgh = plain.height();
 
+        // sdl picks the scaling of a texture when it is created
+        let quality = match self.filter{
+            RenderFilter::Nearest => "nearest",
+            RenderFilter::Linear => "linear",
+        };
+        sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", quality);
+
         let mut texture = self
             .texture_creator
             .create_texture_streaming(PixelFormatEnum::RGBA32, imgw, imgh)
@@ -99,37 +153,218 @@ impl EIWindow{
     }
 
     pub fn redraw(&mut self){
+        if let Err(e) = self.draw_panels(){
+            println!("Editimg: could not draw text: {e}");
+        }
+        if let Err(e) = self.draw_status(){
+            println!("Editimg: could not draw status bar: {e}");
+        }
         self.canvas.present();
     }
 
     pub fn resize_redraw(&mut self, winw: u32, winh: u32) -> Result<(), String>{
         self.winw = winw;
         self.winh = winh;
-        self.canvas.clear();
-        self.draw_texture(winw, winh)?;
-        let rects = std::mem::take(&mut self.rects);
-        for (px, py, qx, qy) in &rects{
-            self._draw_rect(*px, *py, *qx, *qy)?;
-        }
-        self.rects = rects;
+        self.redraw_all()?;
         self.redraw();
         Ok(())
     }
 
     pub fn draw_texture(&mut self, winw: u32, winh: u32) -> Result<(), String>{
         if let Some(texture) = &self.texture{
-            let (x, y, w, h) = resize_dims(self.texw, self.texh, winw, winh);
+            let (x, y, w, h) = view_dims(
+                self.texw, self.texh, winw, winh, self.zoom, self.panx, self.pany
+            );
             self.canvas.copy(texture, None, Some(Rect::new(x, y, w, h)))?;
             self.imgx = x;
             self.imgy = y;
             self.imgw = w;
             self.imgh = h;
+            if self.grid{
+                self.draw_grid()?;
+            }
             Ok(())
         } else {
             Err("Editimg error: window redraw with no valid texture available.".to_string())
         }
     }
 
+    /// Render the image and its rects again without presenting, redraw adds the panels and
+    /// presents.
+    pub fn redraw_all(&mut self) -> Result<(), String>{
+        self.canvas.clear();
+        self.draw_texture(self.winw, self.winh)?;
+        let rects = std::mem::take(&mut self.rects);
+        for (px, py, qx, qy) in &rects{
+            self._draw_rect(*px, *py, *qx, *qy)?;
+        }
+        self.rects = rects;
+        Ok(())
+    }
+
+    /// Zoom in by a factor, keeping the center of the window in place.
+    pub fn zoom(&mut self, f: f32){
+        if f <= 0.0 || !f.is_finite() { return; }
+        self.zoom *= f;
+        self.panx *= f;
+        self.pany *= f;
+    }
+
+    /// Move the image with (dx, dy) window pixels.
+    pub fn pan(&mut self, dx: f32, dy: f32){
+        self.panx += dx;
+        self.pany += dy;
+    }
+
+    pub fn zoom_to_fit(&mut self){
+        self.zoom = 1.0;
+        self.panx = 0.0;
+        self.pany = 0.0;
+    }
+
+    /// Zoom and pan so the given texture pixels fill the window.
+    pub fn zoom_to_rect(&mut self, r: RectXY){
+        if let Some((zoom, panx, pany)) = rect_view(self.texw, self.texh, self.winw, self.winh, r){
+            self.zoom = zoom;
+            self.panx = panx;
+            self.pany = pany;
+        }
+    }
+
+    /// Takes effect the next time a texture is set.
+    pub fn set_filter(&mut self, filter: RenderFilter){
+        self.filter = filter;
+    }
+
+    pub fn set_grid(&mut self, grid: bool){
+        self.grid = grid;
+    }
+
+    fn draw_grid(&mut self) -> Result<(), String>{
+        if self.texw == 0 || self.texh == 0 { return Ok(()); }
+        let sx = self.imgw as f32 / self.texw as f32;
+        let sy = self.imgh as f32 / self.texh as f32;
+        if sx < GRID_MIN_SCALE || sy < GRID_MIN_SCALE { return Ok(()); }
+        let (winw, winh) = (self.winw as i32, self.winh as i32);
+        let top = self.imgy.max(0);
+        let bottom = (self.imgy + self.imgh as i32).min(winh);
+        let left = self.imgx.max(0);
+        let right = (self.imgx + self.imgw as i32).min(winw);
+        // only the lines that end up inside the window
+        let first_col = (-self.imgx as f32 / sx).floor().max(0.0) as u32;
+        let first_row = (-self.imgy as f32 / sy).floor().max(0.0) as u32;
+        let dc = self.canvas.draw_color();
+        let bm = self.canvas.blend_mode();
+        self.canvas.set_blend_mode(BlendMode::Blend);
+        self.canvas.set_draw_color(Color::RGBA(128, 128, 128, 96));
+        for col in first_col..=self.texw{
+            let x = self.imgx + (col as f32 * sx) as i32;
+            if x > winw { break; }
+            self.canvas.draw_line(Point::new(x, top), Point::new(x, bottom))?;
+        }
+        for row in first_row..=self.texh{
+            let y = self.imgy + (row as f32 * sy) as i32;
+            if y > winh { break; }
+            self.canvas.draw_line(Point::new(left, y), Point::new(right, y))?;
+        }
+        self.canvas.set_blend_mode(bm);
+        self.canvas.set_draw_color(dc);
+        Ok(())
+    }
+
+    pub fn set_buffer(&mut self, buffer: usize, buffers: usize){
+        self.status.buffer = buffer;
+        self.status.buffers = buffers;
+    }
+
+    pub fn set_mode(&mut self, mode: &str){
+        self.status.mode = mode.to_string();
+    }
+
+    /// Feed printed text, the status bar shows the last line of it.
+    pub fn push_message(&mut self, text: &str){
+        self.status.push(text);
+    }
+
+    /// Text shown on a translucent panel in the top left of the window.
+    pub fn set_overlay(&mut self, lines: Vec<String>){
+        self.overlay = lines;
+    }
+
+    pub fn clear_overlay(&mut self){
+        self.overlay.clear();
+    }
+
+    /// Text of an open prompt, shown on a panel just above the status bar.
+    pub fn set_prompt(&mut self, lines: Vec<String>){
+        self.prompt = lines;
+    }
+
+    fn draw_panels(&mut self) -> Result<(), String>{
+        let status_h = font::GLYPH_H * STATUS_SCALE + 2 * STATUS_PAD;
+        let overlay = std::mem::take(&mut self.overlay);
+        let prompt = std::mem::take(&mut self.prompt);
+        let res = self.draw_panel(&overlay, 0, false)
+            .and_then(|_| self.draw_panel(&prompt, status_h, true));
+        self.overlay = overlay;
+        self.prompt = prompt;
+        res
+    }
+
+    /// Draw lines on a panel at the top, or when at_bottom is set, the given distance above the
+    /// bottom of the window.
+    fn draw_panel(&mut self, lines: &[String], offset: u32, at_bottom: bool)
+        -> Result<(), String>
+    {
+        if lines.is_empty() { return Ok(()); }
+        let line_h = font::GLYPH_H * STATUS_SCALE + PANEL_PAD;
+        let fits = font::fitting_chars(self.winw.saturating_sub(4 * PANEL_PAD), STATUS_SCALE);
+        let lines = lines.iter()
+            .map(|l| l.chars().take(fits).collect::<String>())
+            .collect::<Vec<_>>();
+        let w = lines.iter().map(|l| font::text_width(l, STATUS_SCALE)).max().unwrap_or(0)
+            + 2 * PANEL_PAD;
+        let h = lines.len() as u32 * line_h + PANEL_PAD;
+        let x = PANEL_PAD as i32;
+        let y = if at_bottom {
+            self.winh as i32 - (offset + PANEL_PAD + h) as i32
+        } else {
+            (offset + PANEL_PAD) as i32
+        };
+        let dc = self.canvas.draw_color();
+        let bm = self.canvas.blend_mode();
+        self.canvas.set_blend_mode(BlendMode::Blend);
+        let [r, g, b, a] = self.colours.overlay;
+        self.canvas.set_draw_color(Color::RGBA(r, g, b, a));
+        self.canvas.fill_rect(Rect::new(x, y, w, h))?;
+        self.canvas.set_draw_color(rgb(self.colours.overlay_text));
+        for (i, line) in lines.iter().enumerate(){
+            let ly = y + (PANEL_PAD + i as u32 * line_h) as i32;
+            font::draw_text(&mut self.canvas, line, x + PANEL_PAD as i32, ly, STATUS_SCALE)?;
+        }
+        self.canvas.set_blend_mode(bm);
+        self.canvas.set_draw_color(dc);
+        Ok(())
+    }
+
+    fn draw_status(&mut self) -> Result<(), String>{
+        let h = font::GLYPH_H * STATUS_SCALE + 2 * STATUS_PAD;
+        if self.winh <= h { return Ok(()); }
+        let scale = if self.texw == 0 { 0.0 } else { self.imgw as f32 / self.texw as f32 };
+        let line = self.status.line(self.texw, self.texh, scale);
+        let fits = font::fitting_chars(self.winw.saturating_sub(2 * STATUS_PAD), STATUS_SCALE);
+        let line = line.chars().take(fits).collect::<String>();
+        let y = (self.winh - h) as i32;
+        let dc = self.canvas.draw_color();
+        self.canvas.set_draw_color(rgb(self.colours.status));
+        self.canvas.fill_rect(Rect::new(0, y, self.winw, h))?;
+        self.canvas.set_draw_color(rgb(self.colours.status_text));
+        let (x, y) = (STATUS_PAD as i32, y + STATUS_PAD as i32);
+        font::draw_text(&mut self.canvas, &line, x, y, STATUS_SCALE)?;
+        self.canvas.set_draw_color(dc);
+        Ok(())
+    }
+
     pub fn redraw_texture(&mut self) -> Result<(), String>{
         self.canvas.clear();
         self.draw_texture(self.winw, self.winh)
@@ -162,11 +397,15 @@ impl EIWindow{
         let py = (py * self.imgh as f32 + self.imgy as f32) as i32;
         let qx = (qx * self.imgw as f32 + self.imgx as f32) as i32;
         let qy = (qy * self.imgh as f32 + self.imgy as f32) as i32;
+        // when zoomed in the rectangle can be far bigger than the window, only draw what is visible
+        let (winw, winh) = (self.winw as i32, self.winh as i32);
+        let xs = || (px.max(-1) + (px.max(-1) - px) % 2)..qx.min(winw + 1);
+        let ys = || (py.max(-1) + (py.max(-1) - py) % 2)..qy.min(winh + 1);
         let draw_point_box = |skip: usize, canvas: &mut Canvas<Window>| -> Result<(), String>{
-            let t = (px..qx).skip(skip).step_by(2).map(|x| Point::new(x, py)).collect::<Vec<_>>();
-            let b = (px..qx).skip(skip).step_by(2).map(|x| Point::new(x, qy)).collect::<Vec<_>>();
-            let l = (py..qy).skip(skip).step_by(2).map(|y| Point::new(px, y)).collect::<Vec<_>>();
-            let r = (py..qy).skip(skip).step_by(2).map(|y| Point::new(qx, y)).collect::<Vec<_>>();
+            let t = xs().skip(skip).step_by(2).map(|x| Point::new(x, py)).collect::<Vec<_>>();
+            let b = xs().skip(skip).step_by(2).map(|x| Point::new(x, qy)).collect::<Vec<_>>();
+            let l = ys().skip(skip).step_by(2).map(|y| Point::new(px, y)).collect::<Vec<_>>();
+            let r = ys().skip(skip).step_by(2).map(|y| Point::new(qx, y)).collect::<Vec<_>>();
             canvas.draw_points(t.as_slice())?;
             canvas.draw_points(b.as_slice())?;
             canvas.draw_points(l.as_slice())?;
@@ -182,6 +421,44 @@ impl EIWindow{
     }
 }
 
+impl Status{
+    fn push(&mut self, text: &str){
+        for c in text.chars(){
+            if c == '\n'{
+                self.line_done = !self.message.is_empty();
+                continue;
+            }
+            if self.line_done{
+                self.message.clear();
+                self.line_done = false;
+            }
+            match c{
+                '\x08' => { self.message.pop(); },
+                c if c.is_control() => {},
+                c => self.message.push(c),
+            }
+        }
+    }
+
+    fn line(&self, w: u32, h: u32, scale: f32) -> String{
+        let mut line = format!(
+            "buf {} of {}  {w}x{h}  {:.0}%", self.buffer, self.buffers, scale * 100.0
+        );
+        if !self.mode.is_empty(){
+            line.push_str(&format!("  [{}]", self.mode));
+        }
+        let message = self.message.trim();
+        if !message.is_empty(){
+            line.push_str(&format!("  {message}"));
+        }
+        line
+    }
+}
+
+fn rgb([r, g, b]: [u8; 3]) -> Color{
+    Color::RGB(r, g, b)
+}
+
 fn resize_dims(imgw: u32, imgh: u32, winw: u32, winh: u32) -> (i32, i32, u32, u32){
     let wfac = winw as f32 / imgw as f32;
     let hfac = winh as f32 / imgh as f32;
@@ -193,6 +470,33 @@ fn resize_dims(imgw: u32, imgh: u32, winw: u32, winh: u32) -> (i32, i32, u32, u3
     (x, y, w, h)
 }
 
+fn view_dims(
+    imgw: u32, imgh: u32, winw: u32, winh: u32, zoom: f32, panx: f32, pany: f32
+) -> (i32, i32, u32, u32){
+    let (x, y, w, h) = resize_dims(imgw, imgh, winw, winh);
+    let zw = w as f32 * zoom;
+    let zh = h as f32 * zoom;
+    let zx = x as f32 + (w as f32 - zw) / 2.0 + panx;
+    let zy = y as f32 + (h as f32 - zh) / 2.0 + pany;
+    (zx.round() as i32, zy.round() as i32, zw.round().max(1.0) as u32, zh.round().max(1.0) as u32)
+}
+
+fn rect_view(imgw: u32, imgh: u32, winw: u32, winh: u32, r: RectXY) -> Option<(f32, f32, f32)>{
+    let rw = (r.qx - r.px).unsigned_abs();
+    let rh = (r.qy - r.py).unsigned_abs();
+    if imgw == 0 || imgh == 0 || rw == 0 || rh == 0 { return None; }
+    let (x, y, w, h) = resize_dims(imgw, imgh, winw, winh);
+    let scale = w as f32 / imgw as f32;
+    let zoom = (winw as f32 / (rw as f32 * scale)).min(winh as f32 / (rh as f32 * scale));
+    let rcx = (r.px + r.qx) as f32 / 2.0;
+    let rcy = (r.py + r.qy) as f32 / 2.0;
+    let cx = x as f32 + w as f32 / 2.0;
+    let cy = y as f32 + h as f32 / 2.0;
+    let panx = winw as f32 / 2.0 - cx + w as f32 * zoom / 2.0 - rcx * scale * zoom;
+    let pany = winh as f32 / 2.0 - cy + h as f32 * zoom / 2.0 - rcy * scale * zoom;
+    Some((zoom, panx, pany))
+}
+
 #[cfg(test)]
 mod tests{
 
@@ -215,4 +519,54 @@ mod tests{
         let (x, y, w, h) = resize_dims(50, 100, 100, 100);
         assert_eq!((x, y, w, h), (25, 0, 50, 100));
     }
+
+    #[test]
+    fn test_view_dims(){
+        let (x, y, w, h) = view_dims(100, 100, 100, 100, 1.0, 0.0, 0.0);
+        assert_eq!((x, y, w, h), (0, 0, 100, 100));
+
+        let (x, y, w, h) = view_dims(100, 100, 100, 100, 2.0, 0.0, 0.0);
+        assert_eq!((x, y, w, h), (-50, -50, 200, 200));
+
+        let (x, y, w, h) = view_dims(100, 100, 100, 100, 2.0, 10.0, -20.0);
+        assert_eq!((x, y, w, h), (-40, -70, 200, 200));
+
+        let (x, y, w, h) = view_dims(100, 50, 100, 100, 0.5, 0.0, 0.0);
+        assert_eq!((x, y, w, h), (25, 38, 50, 25));
+    }
+
+    #[test]
+    fn test_rect_view(){
+        let (zoom, panx, pany) =
+            rect_view(100, 100, 100, 100, RectXY::new(0, 0, 100, 100)).unwrap();
+        assert_eq!((zoom, panx, pany), (1.0, 0.0, 0.0));
+
+        let (zoom, panx, pany) =
+            rect_view(100, 100, 100, 100, RectXY::new(0, 0, 50, 50)).unwrap();
+        assert_eq!((zoom, panx, pany), (2.0, 50.0, 50.0));
+        assert_eq!(view_dims(100, 100, 100, 100, zoom, panx, pany), (0, 0, 200, 200));
+
+        let (zoom, panx, pany) =
+            rect_view(200, 200, 100, 100, RectXY::new(150, 50, 200, 100)).unwrap();
+        assert_eq!((zoom, panx, pany), (4.0, -150.0, 50.0));
+        assert_eq!(view_dims(200, 200, 100, 100, zoom, panx, pany), (-300, -100, 400, 400));
+
+        assert!(rect_view(100, 100, 100, 100, RectXY::new(10, 10, 10, 50)).is_none());
+    }
+
+    #[test]
+    fn test_status(){
+        let mut s = Status::default();
+        s.push("[FX]");
+        assert_eq!(s.message, "[FX]");
+        s.push("\nsrc: 12\x08");
+        assert_eq!(s.message, "src: 1");
+        s.push("\n\n");
+        assert_eq!(s.message, "src: 1");
+        s.buffers = 2;
+        s.mode = "NORMAL".to_string();
+        assert_eq!(s.line(640, 480, 0.5), "buf 0 of 2  640x480  50%  [NORMAL]  src: 1");
+        s.push("saved!");
+        assert_eq!(s.message, "saved!");
+    }
 }